@@ -39,4 +39,5 @@ case ${BUILD_MODE} in
 esac
 
 cargo build --verbose ${build_mode}
+cargo clippy --verbose ${build_mode} --all-targets -- -D warnings
 cargo test --verbose ${build_mode} -- --nocapture
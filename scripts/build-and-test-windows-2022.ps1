@@ -113,9 +113,11 @@ sed -i "s/force-dynamic/force-static/g" ".\Cargo.toml"
 
 if ( "$env:BUILD_MODE" -eq "debug" ) {
     cargo build --verbose
+    cargo clippy --verbose --all-targets -- -D warnings
     cargo test --verbose -- --nocapture
 } elseif ( "$env:BUILD_MODE" -eq "release" ) {
     cargo build --verbose --release
+    cargo clippy --verbose --release --all-targets -- -D warnings
     cargo test --verbose --release -- --nocapture
 } else {
     Write-Error "Unknown build mode: $env:BUILD_MODE"
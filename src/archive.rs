@@ -0,0 +1,188 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Write a static archive (`.a`) of kernel object files with `--archive`.
+//! The archive is written in the GNU format (or in the BSD format for MacOS targets) with a symbol
+//! table listing the kernel defined by each member, so that linkers can resolve the kernels
+//! without running `ranlib`.
+//! The archive is deterministic (i.e., timestamps, owners, and modes are fixed).
+
+use std::fs::File;
+use std::io::Write;
+
+use crate::options;
+
+use options::HostOS;
+
+const MAGIC: &str = "!<arch>\n";
+const HEADER_SIZE: usize = 60;
+
+/// An object file in the archive, which defines the symbols in `symbols`.
+pub struct ArchiveMember {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub symbols: Vec<String>,
+}
+
+impl ArchiveMember {
+    pub fn new(name: String, data: Vec<u8>, symbols: Vec<String>) -> Self {
+        ArchiveMember{name, data, symbols}
+    }
+}
+
+pub struct ArchiveWriter {}
+
+impl ArchiveWriter {
+    /// Writes the archive of `members` to file `f` in the format used by `os`.
+    pub fn write(f: &str, members: &[ArchiveMember], os: HostOS) -> bool {
+        let bytes: Vec<u8> = if os == HostOS::MacOS { Self::gen_bsd(members) } else { Self::gen_gnu(members) };
+        let mut file = match File::create(f) {
+            Ok(file)    => file,
+            Err(msg)    => {
+                eprintln!("Failed to open output file '{}': {}", f, msg);
+                return false;
+            },
+        };
+        match file.write_all(&bytes) {
+            Ok(())      => true,
+            Err(msg)    => {
+                eprintln!("Failed to write to output file '{}': {}", f, msg);
+                false
+            },
+        }
+    }
+
+    fn push_header(bytes: &mut Vec<u8>, name: &str, size: usize) -> () {
+        let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, size);
+        bytes.extend_from_slice(header.as_bytes());
+    }
+
+    /// Returns the archive in the GNU format: member names longer than 15 bytes are stored in the
+    /// `//` member, and the `/` member maps each symbol to the offset of its member (big-endian).
+    fn gen_gnu(members: &[ArchiveMember]) -> Vec<u8> {
+        let mut long_names = String::new();
+        let mut header_names: Vec<String> = Vec::new();
+        for member in members {
+            if member.name.len() < 16 {
+                header_names.push(format!("{}/", member.name));
+            } else {
+                header_names.push(format!("/{}", long_names.len()));
+                long_names += format!("{}/\n", member.name).as_str();
+            }
+        }
+        let n_symbols: usize = members.iter().map(|member| member.symbols.len()).sum();
+        let symbol_names_size: usize = members.iter().flat_map(|m| m.symbols.iter()).map(|s| s.len() + 1).sum();
+        let symtab_size = 4 + 4*n_symbols + symbol_names_size;
+        let mut offset = MAGIC.len() + HEADER_SIZE + symtab_size + symtab_size % 2;
+        if !long_names.is_empty() {
+            offset += HEADER_SIZE + long_names.len() + long_names.len() % 2;
+        }
+        let mut member_offsets: Vec<usize> = Vec::new();
+        for member in members {
+            member_offsets.push(offset);
+            offset += HEADER_SIZE + member.data.len() + member.data.len() % 2;
+        }
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(offset);
+        bytes.extend_from_slice(MAGIC.as_bytes());
+        Self::push_header(&mut bytes, "/", symtab_size);
+        bytes.extend_from_slice(&(n_symbols as u32).to_be_bytes());
+        for (member, member_offset) in members.iter().zip(member_offsets.iter()) {
+            for _ in member.symbols.iter() {
+                bytes.extend_from_slice(&(*member_offset as u32).to_be_bytes());
+            }
+        }
+        for symbol in members.iter().flat_map(|member| member.symbols.iter()) {
+            bytes.extend_from_slice(symbol.as_bytes());
+            bytes.push(0);
+        }
+        Self::push_padding(&mut bytes, symtab_size);
+        if !long_names.is_empty() {
+            Self::push_header(&mut bytes, "//", long_names.len());
+            bytes.extend_from_slice(long_names.as_bytes());
+            Self::push_padding(&mut bytes, long_names.len());
+        }
+        for (member, header_name) in members.iter().zip(header_names.iter()) {
+            Self::push_header(&mut bytes, header_name, member.data.len());
+            bytes.extend_from_slice(&member.data);
+            Self::push_padding(&mut bytes, member.data.len());
+        }
+        bytes
+    }
+
+    /// Returns the archive in the BSD format: member names are stored after the header (`#1/<n>`),
+    /// and the `__.SYMDEF` member maps each symbol (with the Mach-O `_` prefix) to the offset of its
+    /// member (little-endian).
+    /// Every member is padded so that headers and object files are 8-byte aligned.
+    fn gen_bsd(members: &[ArchiveMember]) -> Vec<u8> {
+        let mut symbols: Vec<(String, usize)> = Vec::new();
+        let mut offset = MAGIC.len();
+        for member in members {
+            for symbol in member.symbols.iter() {
+                symbols.push((format!("_{}", symbol), offset));
+            }
+            offset += Self::get_bsd_member_size(&member.name, member.data.len());
+        }
+        let mut string_table_size: usize = symbols.iter().map(|(symbol, _)| symbol.len() + 1).sum();
+        string_table_size += Self::get_padding(string_table_size);
+        let symtab_size = 4 + 8*symbols.len() + 4 + string_table_size;
+        let symtab_member_size = Self::get_bsd_member_size("__.SYMDEF", symtab_size);
+
+        let mut symtab: Vec<u8> = Vec::with_capacity(symtab_size);
+        symtab.extend_from_slice(&((8*symbols.len()) as u32).to_le_bytes());
+        let mut string_offset: usize = 0;
+        for (symbol, member_offset) in symbols.iter() {
+            symtab.extend_from_slice(&(string_offset as u32).to_le_bytes());
+            symtab.extend_from_slice(&((symtab_member_size + member_offset) as u32).to_le_bytes());
+            string_offset += symbol.len() + 1;
+        }
+        symtab.extend_from_slice(&(string_table_size as u32).to_le_bytes());
+        for (symbol, _) in symbols.iter() {
+            symtab.extend_from_slice(symbol.as_bytes());
+            symtab.push(0);
+        }
+        symtab.resize(symtab_size, 0);
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(symtab_member_size + offset);
+        bytes.extend_from_slice(MAGIC.as_bytes());
+        Self::push_bsd_member(&mut bytes, "__.SYMDEF", &symtab);
+        for member in members {
+            Self::push_bsd_member(&mut bytes, &member.name, &member.data);
+        }
+        bytes
+    }
+
+    /// Returns the size of the name of a BSD member (NUL terminated and padded so that the data
+    /// of the member is 8-byte aligned).
+    fn get_bsd_name_size(name: &str) -> usize {
+        name.len() + 1 + Self::get_padding(HEADER_SIZE + name.len() + 1)
+    }
+
+    /// Returns the size of a BSD member, including its header and name.
+    fn get_bsd_member_size(name: &str, size: usize) -> usize {
+        HEADER_SIZE + Self::get_bsd_name_size(name) + size + Self::get_padding(size)
+    }
+
+    fn push_bsd_member(bytes: &mut Vec<u8>, name: &str, data: &[u8]) -> () {
+        let name_size = Self::get_bsd_name_size(name);
+        let data_size = data.len() + Self::get_padding(data.len());
+        Self::push_header(bytes, &format!("#1/{}", name_size), name_size + data_size);
+        let start = bytes.len();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.resize(start + name_size, 0);
+        bytes.extend_from_slice(data);
+        bytes.resize(start + name_size + data_size, b'\n');
+    }
+
+    /// Returns the number of bytes needed to align `size` bytes to 8 bytes (for the BSD format).
+    fn get_padding(size: usize) -> usize {
+        (8 - size % 8) % 8
+    }
+
+    /// Pads a member of `size` bytes to an even size (as required by the GNU format).
+    fn push_padding(bytes: &mut Vec<u8>, size: usize) -> () {
+        if size % 2 == 1 {
+            bytes.push(b'\n');
+        }
+    }
+}
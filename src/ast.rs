@@ -4,6 +4,10 @@
 extern crate llvm_sys as llvm;
 use llvm::prelude::LLVMValueRef;
 
+use crate::lex;
+
+use lex::Span;
+
 pub type GenResult = Result<LLVMValueRef, &'static str>;
 
 pub trait AstGenerator {
@@ -18,7 +22,9 @@ pub trait Ast {
     fn accept(&self, visitor: &mut dyn AstVisitor) -> bool;
     fn accept_gen(&self, visitor: &mut dyn AstGenerator) -> GenResult;
     fn is_expr(&self) -> bool;
-    fn get_expr(&self) -> &ExprKind;
+    fn get_expr(&self) -> &ExprKind<'_>;
+    fn get_span(&self) -> Span;
+    fn get_decl_spans(&self) -> &[Span];
     fn get_vars(&self) -> usize;
     fn to_string(&self) -> String;
 }
@@ -53,6 +59,32 @@ pub fn op_to_string(op: &Operator) -> String {
     }
 }
 
+pub fn op_to_symbol(op: &Operator) -> String {
+    match op {
+        Operator::Add   => String::from("+"),
+        Operator::Div   => String::from("/"),
+        Operator::Mul   => String::from("*"),
+        Operator::Sub   => String::from("-"),
+    }
+}
+
+/// Applies `op` to the operands with the semantics of the generated code (`nsw` and `sdiv`),
+/// returning an error for the cases where the generated code would produce poison or be undefined.
+pub fn op_apply(op: &Operator, n_left: i64, n_right: i64) -> Result<i64, &'static str> {
+    let result = match op {
+        Operator::Add   => n_left.checked_add(n_right),
+        Operator::Div   => {
+            if n_right == 0 {
+                return Err("Division by zero");
+            }
+            n_left.checked_div(n_right)
+        },
+        Operator::Mul   => n_left.checked_mul(n_right),
+        Operator::Sub   => n_left.checked_sub(n_right),
+    };
+    result.ok_or("Integer overflow")
+}
+
 pub type Vars = Vec<String>;
 
 #[derive(Clone,Default)]
@@ -62,20 +94,37 @@ pub enum ExprKind<'a> {
     Factor(Factor),
     BinaryOp(Operator, &'a Expr<'a>, &'a Expr<'a>),
     WithDecl(Vars, &'a Expr<'a>),
+    NameDecl(String, &'a Expr<'a>),
+    ExternDecl(String, Vars, &'a Expr<'a>),
+    Call(String, Vec<&'a Expr<'a>>),
 }
 
 pub fn vars_to_string(vars: &Vars) -> String {
     format!("Vars([{}])", vars.join(","))
 }
 
+/// Nodes synthesized by the parser (e.g., the zero operand of a unary minus) have an empty span.
+/// The spans of the names declared by a WithDecl are recorded in `decl_spans` (when parsed).
 pub struct Expr<'a> {
     expr: ExprKind<'a>,
+    span: Span,
+    decl_spans: Vec<Span>,
     vars: usize,
 }
 
 impl <'a> Expr<'a> {
     pub fn new(expr: ExprKind<'a>, n: usize) -> Self {
-        Expr{expr, vars: n}
+        Expr{expr, span: Default::default(), decl_spans: Vec::new(), vars: n}
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    pub fn with_decl_spans(mut self, spans: Vec<Span>) -> Self {
+        self.decl_spans = spans;
+        self
     }
 
     pub fn new_number(n: i64) -> Self {
@@ -87,12 +136,48 @@ impl <'a> Expr<'a> {
     }
 
     pub fn new_binop(op: Operator, e_left: &'a Expr<'a>, e_right: &'a Expr<'a>) -> Self {
-        Expr::new(ExprKind::BinaryOp(op, e_left, e_right), e_left.vars + e_right.vars)
+        let span = Span::new(e_left.span.start, e_right.span.end);
+        Expr::new(ExprKind::BinaryOp(op, e_left, e_right), e_left.vars + e_right.vars).with_span(span)
     }
 
     pub fn new_withdecl(vars: Vars, e: &'a Expr<'a>) -> Self {
         let n = vars.len();
-        Expr::new(ExprKind::WithDecl(vars, e), n + e.vars)
+        let span = e.span;
+        Expr::new(ExprKind::WithDecl(vars, e), n + e.vars).with_span(span)
+    }
+
+    pub fn new_namedecl(name: String, e: &'a Expr<'a>) -> Self {
+        let span = e.span;
+        Expr::new(ExprKind::NameDecl(name, e), e.vars).with_span(span)
+    }
+
+    /// The parameters of an extern function only give its arity, so they are not counted as vars.
+    pub fn new_externdecl(name: String, params: Vars, e: &'a Expr<'a>) -> Self {
+        let span = e.span;
+        Expr::new(ExprKind::ExternDecl(name, params, e), e.vars).with_span(span)
+    }
+
+    pub fn new_call(name: String, args: Vec<&'a Expr<'a>>) -> Self {
+        let n = args.iter().map(|arg| arg.vars).sum();
+        Expr::new(ExprKind::Call(name, args), n)
+    }
+}
+
+/// Returns the kernel name declared by the program (i.e., by a `name` declaration at the root).
+pub fn get_name_decl(ast: &dyn Ast) -> Option<String> {
+    match ast.get_expr() {
+        ExprKind::NameDecl(name, _) => Some(name.clone()),
+        _                           => None,
+    }
+}
+
+/// Returns the vars of the `with` declaration of the program (i.e., the parameters of the kernel).
+pub fn get_decl_vars(ast: &dyn Ast) -> Vars {
+    match ast.get_expr() {
+        ExprKind::NameDecl(_, e)        => get_decl_vars(*e),
+        ExprKind::ExternDecl(_, _, e)   => get_decl_vars(*e),
+        ExprKind::WithDecl(vars, _)     => vars.clone(),
+        _                               => Vars::new(),
     }
 }
 
@@ -109,10 +194,18 @@ impl <'a> Ast for Expr<'a> {
         true
     }
 
-    fn get_expr(&self) -> &ExprKind {
+    fn get_expr(&self) -> &ExprKind<'_> {
         &self.expr    
     }
 
+    fn get_span(&self) -> Span {
+        self.span
+    }
+
+    fn get_decl_spans(&self) -> &[Span] {
+        &self.decl_spans
+    }
+
     fn get_vars(&self) -> usize {
         self.vars
     }
@@ -127,6 +220,14 @@ impl <'a> Ast for Expr<'a> {
             ExprKind::WithDecl(vars, e)             => {
                 format!("WithDecl({},{})", vars_to_string(vars), e.to_string())
             }
+            ExprKind::NameDecl(name, e)             => format!("NameDecl({},{})", name, e.to_string()),
+            ExprKind::ExternDecl(name, params, e)   => {
+                format!("ExternDecl({},{},{})", name, vars_to_string(params), e.to_string())
+            }
+            ExprKind::Call(name, args)              => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                format!("Call({},[{}])", name, args.join(","))
+            }
         }
     }
 }
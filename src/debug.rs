@@ -0,0 +1,277 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Generate DWARF debug info for the kernel with `-g`.
+//! Source locations are derived from the byte spans of the AST nodes, so every instruction emitted
+//! by `IRGen` maps back to the line and column of the calc expression it was generated for.
+//! The parameters of the `with` declaration are described as variables of the kernel subprogram.
+//! Without `-g` (i.e., for the source annotations of `--asm-source`), only the line table is emitted.
+
+extern crate llvm_sys as llvm;
+
+use llvm::core::LLVMAddModuleFlag;
+use llvm::core::LLVMConstInt;
+use llvm::core::LLVMSetCurrentDebugLocation2;
+use llvm::core::LLVMValueAsMetadata;
+use llvm::debuginfo::LLVMCreateDIBuilder;
+use llvm::debuginfo::LLVMDebugMetadataVersion;
+use llvm::debuginfo::LLVMDIBuilderCreateBasicType;
+use llvm::debuginfo::LLVMDIBuilderCreateCompileUnit;
+use llvm::debuginfo::LLVMDIBuilderCreateDebugLocation;
+use llvm::debuginfo::LLVMDIBuilderCreateExpression;
+use llvm::debuginfo::LLVMDIBuilderCreateFile;
+use llvm::debuginfo::LLVMDIBuilderCreateFunction;
+use llvm::debuginfo::LLVMDIBuilderCreateParameterVariable;
+use llvm::debuginfo::LLVMDIBuilderCreateSubroutineType;
+use llvm::debuginfo::LLVMDIBuilderFinalize;
+use llvm::debuginfo::LLVMDIBuilderInsertDeclareAtEnd;
+use llvm::debuginfo::LLVMDIFlagPrototyped;
+use llvm::debuginfo::LLVMDIFlagZero;
+use llvm::debuginfo::LLVMDisposeDIBuilder;
+use llvm::debuginfo::LLVMSetSubprogram;
+use llvm::debuginfo::LLVMDWARFEmissionKind;
+use llvm::debuginfo::LLVMDWARFSourceLanguage;
+use llvm::prelude::LLVMBasicBlockRef;
+use llvm::prelude::LLVMBool;
+use llvm::prelude::LLVMDIBuilderRef;
+use llvm::prelude::LLVMMetadataRef;
+use llvm::prelude::LLVMValueRef;
+use llvm::LLVMModuleFlagBehavior;
+
+use std::env;
+use std::ffi::c_char;
+use std::ffi::c_uint;
+use std::ptr;
+
+use crate::lex;
+use crate::module;
+use crate::options;
+
+use lex::Span;
+use module::ModuleBundle;
+use options::HostOS;
+use options::OptLevel;
+use options::RunOptions;
+
+/// DWARF base type encoding for signed integers (DW_ATE_signed).
+const DW_ATE_SIGNED: c_uint = 0x05;
+
+pub struct DebugInfo<'a> {
+    builder: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+    is_optimized: bool,
+    line_starts: Vec<usize>,
+    source: &'a str,
+    subprogram: Option<LLVMMetadataRef>,
+    t_i64: LLVMMetadataRef,
+}
+
+impl <'a> DebugInfo<'a> {
+    /// Creates the compile unit for `source` (read from `file_name`) in the module of `bundle`.
+    pub fn new(
+        bundle: &ModuleBundle,
+        source: &'a str,
+        file_name: &str,
+        producer: &str,
+        options: &RunOptions,
+    ) -> Self {
+        let dir = match env::current_dir() {
+            Ok(dir) => dir.to_string_lossy().to_string(),
+            Err(_)  => String::from("."),
+        };
+        let is_optimized = !matches!(options.opt_level, OptLevel::O0);
+        let emission_kind = if options.debug_info {
+            LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull
+        } else {
+            LLVMDWARFEmissionKind::LLVMDWARFEmissionKindLineTablesOnly
+        };
+        let dwarf_version: u64 = if options.get_target_os() == HostOS::MacOS { 4 } else { 5 };
+        let name_i64 = "i64";
+        unsafe {
+            let builder = LLVMCreateDIBuilder(bundle.module);
+            let file = LLVMDIBuilderCreateFile(
+                builder,
+                file_name.as_ptr() as *const c_char,
+                file_name.len(),
+                dir.as_ptr() as *const c_char,
+                dir.len(),
+            );
+            let _compile_unit = LLVMDIBuilderCreateCompileUnit(
+                builder,
+                LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+                file,
+                producer.as_ptr() as *const c_char,
+                producer.len(),
+                is_optimized as LLVMBool,
+                ptr::null(),
+                0,
+                0,
+                ptr::null(),
+                0,
+                emission_kind,
+                0,
+                false as LLVMBool,
+                false as LLVMBool,
+                ptr::null(),
+                0,
+                ptr::null(),
+                0,
+            );
+            let t_i64 = LLVMDIBuilderCreateBasicType(
+                builder,
+                name_i64.as_ptr() as *const c_char,
+                name_i64.len(),
+                64,
+                DW_ATE_SIGNED,
+                LLVMDIFlagZero,
+            );
+            Self::add_module_flag(bundle, "Dwarf Version", dwarf_version);
+            Self::add_module_flag(bundle, "Debug Info Version", LLVMDebugMetadataVersion() as u64);
+            DebugInfo{
+                builder,
+                file,
+                is_optimized,
+                line_starts: Self::get_line_starts(source),
+                source,
+                subprogram: None,
+                t_i64,
+            }
+        }
+    }
+
+    fn add_module_flag(bundle: &ModuleBundle, key: &str, value: u64) -> () {
+        unsafe {
+            let value = LLVMValueAsMetadata(LLVMConstInt(bundle.t_i32, value, false as LLVMBool));
+            LLVMAddModuleFlag(
+                bundle.module,
+                LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+                key.as_ptr() as *const c_char,
+                key.len(),
+                value,
+            );
+        }
+    }
+
+    fn get_line_starts(source: &str) -> Vec<usize> {
+        let mut line_starts: Vec<usize> = vec![0];
+        for (i, c) in source.char_indices() {
+            if c == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        line_starts
+    }
+
+    /// Returns the (1-based) line and column of the byte `offset` in the source.
+    /// The column counts characters (not bytes), so it is not skewed by multibyte identifiers.
+    fn get_line_col(&self, offset: usize) -> (c_uint, c_uint) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line)    => line,
+            Err(line)   => line - 1,
+        };
+        let col = self.source[self.line_starts[line]..offset].chars().count() + 1;
+        ((line + 1) as c_uint, col as c_uint)
+    }
+
+    fn get_location(&self, bundle: &ModuleBundle, offset: usize) -> LLVMMetadataRef {
+        let scope = self.subprogram.expect("Missing subprogram for debug location");
+        let (line, col) = self.get_line_col(offset);
+        unsafe { LLVMDIBuilderCreateDebugLocation(bundle.context, line, col, scope, ptr::null_mut()) }
+    }
+
+    /// Describes the kernel function `f` (named `f_name`) with `n` parameters, declared by the
+    /// program `span`.
+    pub fn gen_subprogram(
+        &mut self,
+        f: LLVMValueRef,
+        f_name: &str,
+        n: usize,
+        span: Span,
+    ) -> () {
+        let (line, _) = self.get_line_col(span.start);
+        let mut types: Vec<LLVMMetadataRef> = vec![self.t_i64; n + 1];
+        unsafe {
+            let t_f = LLVMDIBuilderCreateSubroutineType(
+                self.builder,
+                self.file,
+                types.as_mut_ptr(),
+                types.len() as c_uint,
+                LLVMDIFlagZero,
+            );
+            let subprogram = LLVMDIBuilderCreateFunction(
+                self.builder,
+                self.file,
+                f_name.as_ptr() as *const c_char,
+                f_name.len(),
+                f_name.as_ptr() as *const c_char,
+                f_name.len(),
+                self.file,
+                line,
+                t_f,
+                false as LLVMBool,
+                true as LLVMBool,
+                line,
+                LLVMDIFlagPrototyped,
+                self.is_optimized as LLVMBool,
+            );
+            LLVMSetSubprogram(f, subprogram);
+            self.subprogram = Some(subprogram);
+        }
+    }
+
+    /// Describes parameter `var` (number `i` from zero) of the kernel, stored at `alloca_value`
+    /// and declared at `span`.
+    pub fn gen_param(
+        &self,
+        bundle: &ModuleBundle,
+        var: &str,
+        i: usize,
+        alloca_value: LLVMValueRef,
+        bb: LLVMBasicBlockRef,
+        span: Span,
+    ) -> () {
+        let scope = self.subprogram.expect("Missing subprogram for parameter");
+        let offset = span.start;
+        let (line, _) = self.get_line_col(offset);
+        unsafe {
+            let var_info = LLVMDIBuilderCreateParameterVariable(
+                self.builder,
+                scope,
+                var.as_ptr() as *const c_char,
+                var.len(),
+                (i + 1) as c_uint,
+                self.file,
+                line,
+                self.t_i64,
+                true as LLVMBool,
+                LLVMDIFlagZero,
+            );
+            let expr = LLVMDIBuilderCreateExpression(self.builder, ptr::null_mut(), 0);
+            let location = self.get_location(bundle, offset);
+            LLVMDIBuilderInsertDeclareAtEnd(self.builder, alloca_value, var_info, expr, location, bb);
+        }
+    }
+
+    /// Sets the location of the instructions built next to the start of `span`.
+    /// Empty spans (i.e., nodes synthesized by the parser) keep the current location.
+    pub fn set_location(&self, bundle: &ModuleBundle, span: Span) -> () {
+        if !span.is_empty() {
+            unsafe { LLVMSetCurrentDebugLocation2(bundle.builder, self.get_location(bundle, span.start)); }
+        }
+    }
+
+    /// Resolves the debug info descriptors and clears the location of the builder (so that code
+    /// generated later in the module is not attributed to the kernel).
+    pub fn finalize(&self, bundle: &ModuleBundle) -> () {
+        unsafe {
+            LLVMSetCurrentDebugLocation2(bundle.builder, ptr::null_mut());
+            LLVMDIBuilderFinalize(self.builder);
+        }
+    }
+}
+
+impl <'a> Drop for DebugInfo<'a> {
+    fn drop(&mut self) -> () {
+        unsafe { LLVMDisposeDIBuilder(self.builder); }
+    }
+}
@@ -0,0 +1,516 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Read an AST serialized in the JSON format written by `--emit-ast=json` (see `serialize.rs`).
+//! The input is validated against the schema (including the format version) before it is handed
+//! to the semantics check, so programmatically generated ASTs fail with a path to the bad node.
+
+use std::str::FromStr;
+
+use crate::ast;
+use crate::exit_code;
+use crate::lex;
+use crate::options;
+use crate::serialize;
+
+use ast::Ast;
+use ast::Expr;
+use ast::Operator;
+use exit_code::exit;
+use exit_code::ExitCode;
+use lex::Lexer;
+use lex::Span;
+use options::RunOptions;
+use serialize::AST_FORMAT_NAME;
+use serialize::AST_FORMAT_VERSION;
+
+pub enum JsonValue {
+    Null,
+    Bool,
+    Number(String),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+pub fn json_value_to_kind_string(value: &JsonValue) -> String {
+    match value {
+        JsonValue::Null         => "null",
+        JsonValue::Bool         => "boolean",
+        JsonValue::Number(_)    => "number",
+        JsonValue::String(_)    => "string",
+        JsonValue::Array(_)     => "array",
+        JsonValue::Object(_)    => "object",
+    }.to_string()
+}
+
+struct JsonParser<'a> {
+    source: &'a str,
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl <'a> JsonParser<'a> {
+    fn new(source: &'a str) -> Self {
+        JsonParser{source, bytes: source.as_bytes(), position: 0}
+    }
+
+    fn error(&self, msg: &str) -> ! {
+        eprintln!("Invalid JSON at byte {}: {}", self.position, msg);
+        exit(ExitCode::ParserError);
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) -> () {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn consume(&mut self, b: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(b) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> () {
+        if !self.consume(b) {
+            self.error(format!("Expected '{}'", b as char).as_str());
+        }
+    }
+
+    fn parse_value(&mut self) -> JsonValue {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{')                  => self.parse_object(),
+            Some(b'[')                  => self.parse_array(),
+            Some(b'"')                  => JsonValue::String(self.parse_string()),
+            Some(b'-' | b'0'..=b'9')    => self.parse_number(),
+            Some(b't')                  => self.parse_literal("true", JsonValue::Bool),
+            Some(b'f')                  => self.parse_literal("false", JsonValue::Bool),
+            Some(b'n')                  => self.parse_literal("null", JsonValue::Null),
+            Some(_)                     => self.error("Expected a value"),
+            None                        => self.error("Unexpected end of input"),
+        }
+    }
+
+    fn parse_literal(&mut self, word: &str, value: JsonValue) -> JsonValue {
+        if !self.source[self.position..].starts_with(word) {
+            self.error(format!("Expected '{}'", word).as_str());
+        }
+        self.position += word.len();
+        value
+    }
+
+    fn collect_digits(&mut self) -> usize {
+        let start = self.position;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.position += 1;
+        }
+        self.position - start
+    }
+
+    fn parse_number(&mut self) -> JsonValue {
+        let start = self.position;
+        if self.peek() == Some(b'-') {
+            self.position += 1;
+        }
+        if self.collect_digits() == 0 {
+            self.error("Expected a digit in number");
+        }
+        if self.peek() == Some(b'.') {
+            self.position += 1;
+            if self.collect_digits() == 0 {
+                self.error("Expected a digit after decimal point");
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.position += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.position += 1;
+            }
+            if self.collect_digits() == 0 {
+                self.error("Expected a digit in exponent");
+            }
+        }
+        JsonValue::Number(self.source[start..self.position].to_string())
+    }
+
+    fn parse_hex4(&mut self) -> u32 {
+        let digits = self.source.get(self.position..self.position + 4);
+        match digits.filter(|s| s.bytes().all(|b| b.is_ascii_hexdigit())).map(|s| u32::from_str_radix(s, 16)) {
+            Some(Ok(n)) => {
+                self.position += 4;
+                n
+            },
+            _           => self.error("Expected 4 hexadecimal digits in unicode escape"),
+        }
+    }
+
+    fn parse_escape(&mut self) -> char {
+        let c: u8 = match self.peek() {
+            Some(c) => c,
+            None    => self.error("Unexpected end of input in escape sequence"),
+        };
+        self.position += 1;
+        match c {
+            b'"'    => '"',
+            b'\\'   => '\\',
+            b'/'    => '/',
+            b'b'    => '\u{08}',
+            b'f'    => '\u{0c}',
+            b'n'    => '\n',
+            b'r'    => '\r',
+            b't'    => '\t',
+            b'u'    => {
+                let mut n: u32 = self.parse_hex4();
+                if (0xd800..0xdc00).contains(&n) && self.source[self.position..].starts_with("\\u") {
+                    self.position += 2;
+                    let low: u32 = self.parse_hex4();
+                    if !(0xdc00..0xe000).contains(&low) {
+                        self.error("Expected a low surrogate in unicode escape");
+                    }
+                    n = 0x10000 + ((n - 0xd800) << 10) + (low - 0xdc00);
+                }
+                match char::from_u32(n) {
+                    Some(c) => c,
+                    None    => self.error("Invalid unicode scalar value in escape"),
+                }
+            },
+            _       => self.error("Invalid escape sequence"),
+        }
+    }
+
+    fn parse_string(&mut self) -> String {
+        self.expect(b'"');
+        let mut s = String::new();
+        loop {
+            let c: char = match self.source[self.position..].chars().next() {
+                Some(c) => c,
+                None    => self.error("Unterminated string"),
+            };
+            self.position += c.len_utf8();
+            match c {
+                '"'                 => return s,
+                '\\'                => s.push(self.parse_escape()),
+                c if c < '\u{20}'   => self.error("Unescaped control character in string"),
+                c                   => s.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> JsonValue {
+        self.expect(b'[');
+        let mut values: Vec<JsonValue> = Vec::new();
+        if !self.consume(b']') {
+            loop {
+                values.push(self.parse_value());
+                if !self.consume(b',') {
+                    break;
+                }
+            }
+            self.expect(b']');
+        }
+        JsonValue::Array(values)
+    }
+
+    fn parse_object(&mut self) -> JsonValue {
+        self.expect(b'{');
+        let mut fields: Vec<(String, JsonValue)> = Vec::new();
+        if !self.consume(b'}') {
+            loop {
+                self.skip_whitespace();
+                let key: String = self.parse_string();
+                if fields.iter().any(|(k, _)| *k == key) {
+                    self.error(format!("Duplicate key '{}' in object", key).as_str());
+                }
+                self.expect(b':');
+                fields.push((key, self.parse_value()));
+                if !self.consume(b',') {
+                    break;
+                }
+            }
+            self.expect(b'}');
+        }
+        JsonValue::Object(fields)
+    }
+
+    fn parse(source: &'a str) -> JsonValue {
+        let mut parser = JsonParser::new(source);
+        let value: JsonValue = parser.parse_value();
+        parser.skip_whitespace();
+        if parser.peek().is_some() {
+            parser.error("Unexpected trailing characters after value");
+        }
+        value
+    }
+}
+
+pub struct AstReader<'a> {
+    options: &'a RunOptions,
+    version: u32,
+}
+
+impl <'a> AstReader<'a> {
+    fn new(options: &'a RunOptions) -> Self {
+        AstReader{options, version: AST_FORMAT_VERSION}
+    }
+
+    fn error(path: &str, msg: &str) -> ! {
+        eprintln!("Invalid AST at '{}': {}", path, msg);
+        exit(ExitCode::ParserError);
+    }
+
+    fn get_object<'b>(value: &'b JsonValue, path: &str) -> &'b [(String, JsonValue)] {
+        match value {
+            JsonValue::Object(fields)   => fields,
+            _                           => Self::error(
+                path,
+                format!("Expected object, found {}", json_value_to_kind_string(value)).as_str()
+            ),
+        }
+    }
+
+    fn check_keys(fields: &[(String, JsonValue)], path: &str, keys: &[&str]) -> () {
+        if let Some((key, _)) = fields.iter().find(|(k, _)| !keys.contains(&k.as_str())) {
+            Self::error(path, format!("Unexpected key '{}'", key).as_str());
+        }
+    }
+
+    /// Returns the fields of the object `value`, rejecting any key not found in `keys`.
+    fn get_fields<'b>(value: &'b JsonValue, path: &str, keys: &[&str]) -> &'b [(String, JsonValue)] {
+        let fields = Self::get_object(value, path);
+        Self::check_keys(fields, path, keys);
+        fields
+    }
+
+    fn find_field<'b>(fields: &'b [(String, JsonValue)], key: &str) -> Option<&'b JsonValue> {
+        fields.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn get_field<'b>(fields: &'b [(String, JsonValue)], key: &str, path: &str) -> &'b JsonValue {
+        match Self::find_field(fields, key) {
+            Some(value) => value,
+            None        => Self::error(path, format!("Missing key '{}'", key).as_str()),
+        }
+    }
+
+    fn get_string<'b>(value: &'b JsonValue, path: &str) -> &'b str {
+        match value {
+            JsonValue::String(s)    => s,
+            _                       => Self::error(
+                path,
+                format!("Expected string, found {}", json_value_to_kind_string(value)).as_str()
+            ),
+        }
+    }
+
+    fn get_integer<T: FromStr>(value: &JsonValue, path: &str, type_name: &str) -> T {
+        let result = match value {
+            JsonValue::Number(text) => T::from_str(text).ok(),
+            _                       => None,
+        };
+        match result {
+            Some(n) => n,
+            None    => Self::error(path, format!("Expected {}", type_name).as_str()),
+        }
+    }
+
+    /// Spans are optional for generated ASTs (i.e., not read from source text) and default to empty.
+    fn read_span(fields: &[(String, JsonValue)], path: &str) -> Span {
+        let value: &JsonValue = match Self::find_field(fields, "span") {
+            Some(value) => value,
+            None        => return Default::default(),
+        };
+        let path_span = format!("{}.span", path);
+        let fields_span = Self::get_fields(value, &path_span, &["start", "end"]);
+        let start: usize = Self::get_integer(
+            Self::get_field(fields_span, "start", &path_span),
+            &format!("{}.start", path_span),
+            "non-negative integer",
+        );
+        let end: usize = Self::get_integer(
+            Self::get_field(fields_span, "end", &path_span),
+            &format!("{}.end", path_span),
+            "non-negative integer",
+        );
+        if start > end {
+            Self::error(&path_span, "Expected 'start' to be less than or equal to 'end'");
+        }
+        Span::new(start, end)
+    }
+
+    fn read_ident(&self, value: &JsonValue, path: &str) -> String {
+        let name: &str = Self::get_string(value, path);
+        if !Lexer::is_ident_text(name, self.options) {
+            Self::error(path, format!("Invalid identifier '{}'", name).as_str());
+        }
+        name.to_string()
+    }
+
+    fn read_op(value: &JsonValue, path: &str) -> Operator {
+        match Self::get_string(value, path) {
+            "Add"   => Operator::Add,
+            "Div"   => Operator::Div,
+            "Mul"   => Operator::Mul,
+            "Sub"   => Operator::Sub,
+            op      => Self::error(path, format!("Unknown operator '{}'", op).as_str()),
+        }
+    }
+
+    fn read_expr_factor(&self, fields: &[(String, JsonValue)], path: &str) -> Box<Expr<'static>> {
+        let span: Span = Self::read_span(fields, path);
+        let expr: Expr = match (Self::find_field(fields, "number"), Self::find_field(fields, "ident")) {
+            (Some(n), None)     => Expr::new_number(Self::get_integer(
+                n,
+                &format!("{}.number", path),
+                "64-bit signed integer",
+            )),
+            (None, Some(name))  => Expr::new_ident(self.read_ident(name, &format!("{}.ident", path))),
+            _                   => Self::error(path, "Expected exactly one of 'number' or 'ident'"),
+        };
+        Box::new(expr.with_span(span))
+    }
+
+    fn read_expr_binop(&self, fields: &[(String, JsonValue)], path: &str) -> Box<Expr<'static>> {
+        let span: Span = Self::read_span(fields, path);
+        let op: Operator = Self::read_op(Self::get_field(fields, "op", path), &format!("{}.op", path));
+        let path_left = format!("{}.lhs", path);
+        let path_right = format!("{}.rhs", path);
+        let e_left = self.read_expr(Self::get_field(fields, "lhs", path), &path_left, &[]);
+        let e_right = self.read_expr(Self::get_field(fields, "rhs", path), &path_right, &[]);
+        Box::new(Expr::new_binop(op, Box::leak(e_left), Box::leak(e_right)).with_span(span))
+    }
+
+    fn read_expr_withdecl(&self, fields: &[(String, JsonValue)], path: &str) -> Box<Expr<'static>> {
+        let span: Span = Self::read_span(fields, path);
+        let path_vars = format!("{}.vars", path);
+        let vars: Vec<String> = match Self::get_field(fields, "vars", path) {
+            JsonValue::Array(values) if !values.is_empty()  => values.iter().enumerate().map(|(i, value)| {
+                self.read_ident(value, &format!("{}[{}]", path_vars, i))
+            }).collect(),
+            _                                               => {
+                Self::error(&path_vars, "Expected non-empty array of identifiers")
+            },
+        };
+        let e = self.read_expr(Self::get_field(fields, "expr", path), &format!("{}.expr", path), &[]);
+        Box::new(Expr::new_withdecl(vars, Box::leak(e)).with_span(span))
+    }
+
+    fn read_expr_namedecl(&self, fields: &[(String, JsonValue)], path: &str) -> Box<Expr<'static>> {
+        let span: Span = Self::read_span(fields, path);
+        let name: String = self.read_ident(Self::get_field(fields, "name", path), &format!("{}.name", path));
+        let path_expr = format!("{}.expr", path);
+        let e = self.read_expr(Self::get_field(fields, "expr", path), &path_expr, &["ExternDecl", "WithDecl"]);
+        Box::new(Expr::new_namedecl(name, Box::leak(e)).with_span(span))
+    }
+
+    fn read_expr_externdecl(&self, fields: &[(String, JsonValue)], path: &str) -> Box<Expr<'static>> {
+        let span: Span = Self::read_span(fields, path);
+        let name: String = self.read_ident(Self::get_field(fields, "name", path), &format!("{}.name", path));
+        let path_params = format!("{}.params", path);
+        let params: Vec<String> = match Self::get_field(fields, "params", path) {
+            JsonValue::Array(values)    => values.iter().enumerate().map(|(i, value)| {
+                self.read_ident(value, &format!("{}[{}]", path_params, i))
+            }).collect(),
+            _                           => Self::error(&path_params, "Expected array of identifiers"),
+        };
+        let path_expr = format!("{}.expr", path);
+        let e = self.read_expr(Self::get_field(fields, "expr", path), &path_expr, &["ExternDecl", "WithDecl"]);
+        Box::new(Expr::new_externdecl(name, params, Box::leak(e)).with_span(span))
+    }
+
+    fn read_expr_call(&self, fields: &[(String, JsonValue)], path: &str) -> Box<Expr<'static>> {
+        let span: Span = Self::read_span(fields, path);
+        let name: String = self.read_ident(Self::get_field(fields, "name", path), &format!("{}.name", path));
+        let path_args = format!("{}.args", path);
+        let args: Vec<&Expr> = match Self::get_field(fields, "args", path) {
+            JsonValue::Array(values)    => values.iter().enumerate().map(|(i, value)| {
+                &*Box::leak(self.read_expr(value, &format!("{}[{}]", path_args, i), &[]))
+            }).collect(),
+            _                           => Self::error(&path_args, "Expected array of nodes"),
+        };
+        Box::new(Expr::new_call(name, args).with_span(span))
+    }
+
+    /// Reads the node `value`, where `decls` are the declaration kinds allowed at its position.
+    fn read_expr(&self, value: &JsonValue, path: &str, decls: &[&str]) -> Box<Expr<'static>> {
+        let fields = Self::get_object(value, path);
+        let kind: &str = Self::get_string(Self::get_field(fields, "kind", path), &format!("{}.kind", path));
+        let keys: &[&str] = match kind {
+            "Factor"    => &["kind", "number", "ident", "span"],
+            "BinaryOp"  => &["kind", "op", "lhs", "rhs", "span"],
+            "WithDecl"  => &["kind", "vars", "expr", "span"],
+            "NameDecl"  => &["kind", "name", "expr", "span"],
+            "ExternDecl" => &["kind", "name", "params", "expr", "span"],
+            "Call"      => &["kind", "name", "args", "span"],
+            _           => Self::error(path, format!("Unknown node kind '{}'", kind).as_str()),
+        };
+        Self::check_keys(fields, path, keys);
+        let version: u32 = Self::get_kind_version(kind);
+        if version > self.version {
+            Self::error(
+                path,
+                format!("Node kind '{}' requires version {} (found version {})", kind, version, self.version).as_str()
+            );
+        }
+        match kind {
+            "Factor"                            => self.read_expr_factor(fields, path),
+            "BinaryOp"                          => self.read_expr_binop(fields, path),
+            "Call"                              => self.read_expr_call(fields, path),
+            _ if !decls.contains(&kind)         => {
+                Self::error(path, format!("Unexpected '{}' below the root node", kind).as_str())
+            },
+            "WithDecl"                          => self.read_expr_withdecl(fields, path),
+            "ExternDecl"                        => self.read_expr_externdecl(fields, path),
+            _                                   => self.read_expr_namedecl(fields, path),
+        }
+    }
+
+    /// Returns the version of the format which introduced the node `kind`.
+    fn get_kind_version(kind: &str) -> u32 {
+        match kind {
+            "NameDecl"              => 2,
+            "ExternDecl" | "Call"   => 3,
+            _                       => 1,
+        }
+    }
+
+    fn read_root(&mut self, value: &JsonValue) -> Box<Expr<'static>> {
+        let path = "$";
+        let fields = Self::get_fields(value, path, &["format", "version", "root"]);
+        let format: &str = Self::get_string(Self::get_field(fields, "format", path), "$.format");
+        if format != AST_FORMAT_NAME {
+            Self::error("$.format", format!("Expected '{}', found '{}'", AST_FORMAT_NAME, format).as_str());
+        }
+        let version: u32 = Self::get_integer(Self::get_field(fields, "version", path), "$.version", "integer");
+        if version == 0 || version > AST_FORMAT_VERSION {
+            Self::error(
+                "$.version",
+                format!("Unsupported version {} (expected at most {})", version, AST_FORMAT_VERSION).as_str()
+            );
+        }
+        if self.options.verbose {
+            eprintln!("Reading AST format '{}' version {}", format, version);
+        }
+        self.version = version;
+        self.read_expr(Self::get_field(fields, "root", path), "$.root", &["NameDecl", "ExternDecl", "WithDecl"])
+    }
+
+    /// Reads the JSON serialization of an AST from `source` in place of the lexer and parser.
+    #[allow(clippy::redundant_allocation)]
+    pub fn read_input(ret: &mut Box<&mut dyn Ast>, source: &str, options: &RunOptions) {
+        let value: JsonValue = JsonParser::parse(source);
+        let mut reader = AstReader::new(options);
+        let expr: Box<Expr<'static>> = reader.read_root(&value);
+        **ret = Box::leak(expr) as &mut dyn Ast;
+        if options.print_ast { eprintln!("AST: {}", ret.to_string()); }
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Evaluate the AST directly for `--eval`, without generating or linking any code.
+//! Arguments are bound to the variables of the `with` declaration in order (as in `MainGen`), and
+//! the result is printed in the same format as the generated main function.
+//! Operations that would produce poison or undefined behavior in the compiled code (signed overflow
+//! and division by zero) are reported as errors.
+
+use std::collections::HashMap;
+
+use crate::ast;
+use crate::exit_code;
+use crate::options;
+
+use ast::op_apply;
+use ast::op_to_symbol;
+use ast::Ast;
+use ast::AstVisitor;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Factor;
+use ast::Operator;
+use ast::Vars;
+use exit_code::exit;
+use exit_code::ExitCode;
+use options::RunOptions;
+
+pub struct Interpreter<'a> {
+    args: &'a [i64],
+    scope: HashMap<String, i64>,
+    value: i64,
+    options: &'a RunOptions,
+}
+
+impl <'a> Interpreter<'a> {
+    fn new(args: &'a [i64], options: &'a RunOptions) -> Self {
+        Interpreter{args, scope: HashMap::new(), value: 0, options}
+    }
+
+    fn eval_expr_undefined(&mut self) -> bool {
+        eprintln!("Unexpected undefined expression in AST");
+        false
+    }
+
+    fn eval_expr_factor(&mut self, f: &Factor) -> bool {
+        self.value = match f {
+            Factor::Number(n)   => *n,
+            Factor::Ident(var)  => match self.scope.get(var) {
+                Some(n) => *n,
+                None    => {
+                    eprintln!("Found unbound var '{}' in scope", var);
+                    return false;
+                },
+            },
+        };
+        true
+    }
+
+    fn eval_expr_binop(&mut self, op: &Operator, e_left: &Expr, e_right: &Expr) -> bool {
+        if !self.visit(e_left) {
+            return false;
+        }
+        let n_left = self.value;
+        if !self.visit(e_right) {
+            return false;
+        }
+        let n_right = self.value;
+        match op_apply(op, n_left, n_right) {
+            Ok(n)       => {
+                self.value = n;
+                true
+            },
+            Err(msg)    => {
+                eprintln!("{} in expression '{} {} {}'", msg, n_left, op_to_symbol(op), n_right);
+                false
+            },
+        }
+    }
+
+    fn eval_expr_withdecl(&mut self, vars: &Vars, e: &Expr) -> bool {
+        for (var, n) in vars.iter().zip(self.args.iter()) {
+            if self.options.verbose {
+                eprintln!("Bound var '{}' to {}", var, n);
+            }
+            self.scope.insert(var.clone(), *n);
+        }
+        self.visit(e)
+    }
+
+    fn eval_expr_call(&mut self, name: &str) -> bool {
+        eprintln!("Calls to extern functions are not supported by the interpreter (found call to '{}')", name);
+        eprintln!("Please use '--run' (with '--link-bitcode' if needed) to call extern functions");
+        false
+    }
+
+    /// Evaluates the program for the given arguments and returns the result.
+    pub fn eval_result(ast: &dyn Ast, args: &[i64], options: &RunOptions) -> Option<i64> {
+        let n = ast.get_vars();
+        if args.len() != n {
+            eprintln!("Invalid number of args to main. Expected {} args", n);
+            return None;
+        }
+        let mut interpreter = Interpreter::new(args, options);
+        if !ast.accept(&mut interpreter) {
+            eprintln!("AST failed evaluation");
+            return None;
+        }
+        Some(interpreter.value)
+    }
+
+    /// Evaluates the program for the given arguments and prints the result.
+    pub fn eval(ast: &dyn Ast, args: &[i64], options: &RunOptions) -> ! {
+        match Self::eval_result(ast, args, options) {
+            Some(n) => println!("calcc_main result: {}", n),
+            None    => exit(ExitCode::EvalError),
+        };
+        exit(ExitCode::Ok);
+    }
+}
+
+impl <'a> AstVisitor for Interpreter<'a> {
+    fn visit(&mut self, ast: &dyn Ast) -> bool {
+        if ast.is_expr() {
+            let expr: &ExprKind = ast.get_expr();
+            return match expr {
+                ExprKind::Undefined                     => self.eval_expr_undefined(),
+                ExprKind::Factor(f)                     => self.eval_expr_factor(f),
+                ExprKind::BinaryOp(op, e_left, e_right) => self.eval_expr_binop(op, e_left, e_right),
+                ExprKind::WithDecl(vars, e)             => self.eval_expr_withdecl(vars, e),
+                ExprKind::NameDecl(_, e)                => self.visit(*e),
+                ExprKind::ExternDecl(_, _, e)           => self.visit(*e),
+                ExprKind::Call(name, _)                 => self.eval_expr_call(name),
+            }
+        }
+        false
+    }
+}
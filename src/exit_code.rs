@@ -20,6 +20,9 @@ pub enum ExitCode {
     LinkError = 11,
     WriteError = 12,
     CommandError = 13,
+    FormatError = 14,
+    EvalError = 15,
+    JitError = 16,
 }
 
 pub fn exit(code: ExitCode) -> ! {
@@ -0,0 +1,118 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Fold the constant subtrees of the AST before IR generation.
+//! Folding uses exact i64 arithmetic, so an operation on literals that would overflow or divide by
+//! zero (i.e., produce poison or undefined behavior in the generated code) is reported as a
+//! compile-time error instead.
+//! Only subtrees made entirely of literals are folded; operations are never reassociated.
+
+use crate::ast;
+use crate::exit_code;
+use crate::lex;
+use crate::options;
+
+use ast::op_apply;
+use ast::op_to_symbol;
+use ast::Ast;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Factor;
+use ast::Operator;
+use exit_code::ExitCode;
+use lex::Span;
+use options::RunOptions;
+
+pub struct ConstFold<'a> {
+    options: &'a RunOptions,
+}
+
+impl <'a> ConstFold<'a> {
+    fn new(options: &'a RunOptions) -> Self {
+        ConstFold{options}
+    }
+
+    fn get_number(e: &Expr) -> Option<i64> {
+        match e.get_expr() {
+            ExprKind::Factor(Factor::Number(n)) => Some(*n),
+            _                                   => None,
+        }
+    }
+
+    fn fold_child(&self, e: &Expr) -> Option<&'static Expr<'static>> {
+        let expr = self.fold_expr(e)?;
+        Some(Box::leak(Box::new(expr)))
+    }
+
+    fn fold_expr_binop(&self, op: &Operator, e_left: &Expr, e_right: &Expr, span: Span) -> Option<Expr<'static>> {
+        let e_left = self.fold_child(e_left)?;
+        let e_right = self.fold_child(e_right)?;
+        let (n_left, n_right) = match (Self::get_number(e_left), Self::get_number(e_right)) {
+            (Some(n_left), Some(n_right))   => (n_left, n_right),
+            _                               => return Some(Expr::new_binop(*op, e_left, e_right).with_span(span)),
+        };
+        match op_apply(op, n_left, n_right) {
+            Ok(n)       => {
+                if self.options.verbose {
+                    eprintln!("Folded '{} {} {}' to '{}'", n_left, op_to_symbol(op), n_right, n);
+                }
+                Some(Expr::new_number(n).with_span(span))
+            },
+            Err(msg)    => {
+                eprintln!(
+                    "{} in constant expression '{} {} {}' at bytes {}..{}",
+                    msg,
+                    n_left,
+                    op_to_symbol(op),
+                    n_right,
+                    span.start,
+                    span.end
+                );
+                None
+            },
+        }
+    }
+
+    /// Returns a copy of the expression with its constant subtrees folded.
+    /// The copy does not borrow from the original tree, so it can replace the original AST.
+    fn fold_expr(&self, ast: &dyn Ast) -> Option<Expr<'static>> {
+        let span = ast.get_span();
+        match ast.get_expr() {
+            ExprKind::Undefined                     => {
+                eprintln!("Unexpected undefined expression in AST");
+                None
+            },
+            ExprKind::Factor(f)                     => {
+                Some(Expr::new(ExprKind::Factor(f.clone()), 0).with_span(span))
+            },
+            ExprKind::BinaryOp(op, e_left, e_right) => self.fold_expr_binop(op, e_left, e_right, span),
+            ExprKind::WithDecl(vars, e)             => {
+                let e = self.fold_child(e)?;
+                Some(Expr::new_withdecl(vars.clone(), e).with_span(span).with_decl_spans(ast.get_decl_spans().to_vec()))
+            },
+            ExprKind::NameDecl(name, e)             => {
+                Some(Expr::new_namedecl(name.clone(), self.fold_child(e)?).with_span(span))
+            },
+            ExprKind::ExternDecl(name, params, e)   => {
+                Some(Expr::new_externdecl(name.clone(), params.clone(), self.fold_child(e)?).with_span(span))
+            },
+            ExprKind::Call(name, args)              => {
+                let args: Vec<&Expr> = args.iter().map(|arg| self.fold_child(arg)).collect::<Option<_>>()?;
+                Some(Expr::new_call(name.clone(), args).with_span(span))
+            },
+        }
+    }
+
+    /// Returns the folded copy of the AST (the original AST is left unchanged).
+    /// Returns an error if a constant subtree fails to fold (the error has already been reported).
+    pub fn fold_all(ast: &dyn Ast, options: &RunOptions) -> Result<&'static mut dyn Ast, ExitCode> {
+        let const_fold = ConstFold::new(options);
+        match const_fold.fold_expr(ast) {
+            Some(expr)  => Ok(Box::leak(Box::new(expr))),
+            None        => {
+                eprintln!("AST failed ConstFold semantics check");
+                Err(ExitCode::SemanticError)
+            },
+        }
+    }
+}
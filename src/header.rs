@@ -0,0 +1,161 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Generate a C header declaring the kernel (or the kernels of an archive) with `--emit-header`.
+//! The prototype is derived from the `FunctionSignature` of the kernel, using the same typedefs as
+//! `main.c.template`, and is named with the parameters of the `with` declaration.
+//! The header can be included from both C and C++ (i.e., the prototype has C linkage).
+
+extern crate llvm_sys as llvm;
+use llvm::prelude::LLVMTypeRef;
+
+use crate::ast;
+use crate::exit_code;
+use crate::module;
+
+use ast::Vars;
+use exit_code::exit;
+use exit_code::ExitCode;
+use module::FunctionSignature;
+use module::ModuleBundle;
+
+/// Keywords of C (up to C23) and C++ (up to C++20) which are valid calc identifiers, and the typedef of the parameters (i.e.,
+/// names which cannot be used as parameter names in the header).
+pub static C_KEYWORDS: [&str; 110] = [
+    "_Alignas", "alignas", "_Alignof", "alignof", "and", "and_eq", "asm", "_Atomic", "auto",
+    "bitand", "_BitInt", "bitor", "_Bool", "bool", "break", "case", "catch", "char", "char16_t",
+    "char32_t", "char8_t", "class", "co_await", "co_return", "co_yield", "compl", "_Complex",
+    "concept", "const", "const_cast", "consteval", "constexpr", "constinit", "continue",
+    "_Decimal128", "_Decimal32", "_Decimal64", "decltype", "default", "delete", "do", "double",
+    "dynamic_cast", "else", "enum", "explicit", "export", "extern", "false", "float", "for",
+    "friend", "_Generic", "goto", "if", "_Imaginary", "inline", "int", "long", "mutable",
+    "namespace", "new", "noexcept", "_Noreturn", "not", "not_eq", "nullptr", "operator", "or",
+    "or_eq", "private", "protected", "public", "register", "reinterpret_cast", "requires",
+    "restrict", "return", "short", "signed", "sizeof", "static", "_Static_assert", "static_assert",
+    "static_cast", "struct", "switch", "template", "this", "_Thread_local", "thread_local", "throw",
+    "true", "try", "typedef", "typeid", "typename", "typeof", "typeof_unqual", "union", "unsigned",
+    "using", "virtual", "void", "volatile", "wchar_t", "while", "xor", "xor_eq", "t_i64",
+];
+
+pub struct HeaderGen {}
+
+impl HeaderGen {
+    /// Returns the text of the header declaring the kernels, where each kernel is given by its
+    /// signature and the names of its parameters, and `producer` identifies the compiler.
+    /// The include guard is derived from `guard_name` (e.g., the kernel or the archive name).
+    pub fn gen(kernels: &[(&FunctionSignature, &Vars)], guard_name: &str, producer: &str) -> String {
+        let guard = format!("CALCC_{}_H", guard_name.to_ascii_uppercase());
+        let mut lines: Vec<String> = vec![
+            Self::get_producer_comment(kernels, producer),
+            format!("#ifndef {}", guard),
+            format!("#define {}", guard),
+            String::new(),
+            "#ifdef __cplusplus".to_string(),
+            "extern \"C\" {".to_string(),
+            "#endif".to_string(),
+            String::new(),
+        ];
+        lines.append(&mut Self::get_typedefs(kernels));
+        for (f_sig, vars) in kernels {
+            lines.push(Self::get_prototype(f_sig, vars));
+        }
+        lines.append(&mut vec![
+            String::new(),
+            "#ifdef __cplusplus".to_string(),
+            "}".to_string(),
+            "#endif".to_string(),
+            String::new(),
+            format!("#endif // {}", guard),
+        ]);
+        lines.join("\n") + "\n"
+    }
+
+    /// Returns the comment naming the compiler and the kernels at the top of generated files.
+    pub fn get_producer_comment(kernels: &[(&FunctionSignature, &Vars)], producer: &str) -> String {
+        let names: Vec<String> = kernels.iter().map(|(f_sig, _)| format!("'{}'", f_sig.name)).collect();
+        let noun = if names.len() == 1 { "kernel" } else { "kernels" };
+        format!("// Generated by {} for {} {}. Do not edit.", producer, noun, names.join(", "))
+    }
+
+    /// Returns the typedefs for each type in the signatures (guarded, so that headers for several
+    /// kernels can be included together).
+    fn get_typedefs(kernels: &[(&FunctionSignature, &Vars)]) -> Vec<String> {
+        let mut type_names: Vec<String> = Vec::new();
+        for (f_sig, _) in kernels {
+            for t in [f_sig.t_ret].iter().chain(f_sig.params.iter()) {
+                let type_name = ModuleBundle::type_name_from(*t);
+                if !type_names.contains(&type_name) {
+                    type_names.push(type_name);
+                }
+            }
+        }
+        let mut lines: Vec<String> = Vec::new();
+        for type_name in type_names {
+            let guard = format!("CALCC_T_{}", type_name.to_ascii_uppercase());
+            lines.push(format!("#ifndef {}", guard));
+            lines.push(format!("#define {}", guard));
+            lines.push(format!("typedef {} t_{};", Self::get_c_type(&type_name), type_name));
+            lines.push("#endif".to_string());
+            lines.push(String::new());
+        }
+        lines
+    }
+
+    fn get_c_type(type_name: &str) -> &'static str {
+        match type_name {
+            "i32"   => "int",
+            "i64"   => "long long",
+            "ptr"   => "void*",
+            _       => {
+                eprintln!("Unsupported type '{}' for header", type_name);
+                exit(ExitCode::WriteError);
+            },
+        }
+    }
+
+    fn get_prototype(f_sig: &FunctionSignature, vars: &Vars) -> String {
+        let names: Vec<String> = Self::get_param_names(vars, f_sig.params.len(), Self::get_param_name);
+        let params: Vec<String> = f_sig.params.iter().zip(names.iter()).map(|(t, name)| {
+            format!("{} {}", Self::get_type_string(*t), name)
+        }).collect();
+        let params_string = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+        format!("extern {} {}({});", Self::get_type_string(f_sig.t_ret), f_sig.name, params_string)
+    }
+
+    fn get_type_string(t: LLVMTypeRef) -> String {
+        format!("t_{}", ModuleBundle::type_name_from(t))
+    }
+
+    /// Returns the names of the `n` parameters, where `get_name` gives the name of each var (or
+    /// `None` if the var cannot be used).
+    /// The other parameters are named `p<i>` (as in `main.c.template`), with trailing underscores
+    /// if needed to be distinct from the names of the other parameters.
+    pub fn get_param_names(vars: &Vars, n: usize, get_name: fn(&str) -> Option<String>) -> Vec<String> {
+        let names: Vec<Option<String>> = (0..n).map(|i| vars.get(i).and_then(|var| get_name(var))).collect();
+        let mut params: Vec<String> = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let param = match name {
+                Some(name)  => name.clone(),
+                None        => {
+                    let mut param = format!("p{}", i);
+                    while names.contains(&Some(param.clone())) || params.contains(&param) {
+                        param.push('_');
+                    }
+                    param
+                },
+            };
+            params.push(param);
+        }
+        params
+    }
+
+    /// Returns the name of parameter `var`, or `None` if it is not a valid C identifier (e.g.,
+    /// with `--unicode-idents`) or is a keyword.
+    fn get_param_name(var: &str) -> Option<String> {
+        if var.is_ascii() && !C_KEYWORDS.contains(&var) {
+            Some(var.to_string())
+        } else {
+            None
+        }
+    }
+}
@@ -3,8 +3,10 @@
 
 extern crate llvm_sys as llvm;
 
+use llvm::core::LLVMAddAttributeAtIndex;
 use llvm::core::LLVMAddFunction;
 use llvm::core::LLVMAppendBasicBlockInContext;
+use llvm::core::LLVMBuildCall2;
 use llvm::core::LLVMBuildLoad2;
 use llvm::core::LLVMBuildNSWAdd;
 use llvm::core::LLVMBuildNSWMul;
@@ -12,20 +14,28 @@ use llvm::core::LLVMBuildNSWSub;
 use llvm::core::LLVMBuildRet;
 use llvm::core::LLVMBuildSDiv;
 use llvm::core::LLVMBuildStore;
+use llvm::core::LLVMCreateEnumAttribute;
 use llvm::core::LLVMFunctionType;
+use llvm::core::LLVMGetEnumAttributeKindForName;
+use llvm::core::LLVMGetInsertBlock;
+use llvm::core::LLVMGetNamedFunction;
 use llvm::core::LLVMGetParam;
+use llvm::core::LLVMGlobalGetValueType;
 use llvm::core::LLVMPositionBuilderAtEnd;
 use llvm::prelude::LLVMBasicBlockRef;
 use llvm::prelude::LLVMBool;
 use llvm::prelude::LLVMTypeRef;
 use llvm::prelude::LLVMValueRef;
+use llvm::LLVMAttributeFunctionIndex;
 
 use std::ffi::c_char;
 use std::ffi::c_uint;
 
 use crate::ast;
-use crate::exit_code;
+use crate::debug;
+use crate::lex;
 use crate::module;
+use crate::options;
 
 use ast::Ast;
 use ast::AstGenerator;
@@ -35,22 +45,33 @@ use ast::Factor;
 use ast::GenResult;
 use ast::Operator;
 use ast::Vars;
-use exit_code::exit;
-use exit_code::ExitCode;
+use debug::DebugInfo;
+use lex::Span;
 use module::FunctionSignature;
 use module::ModuleBundle;
+use options::OptLevel;
 
-pub struct IRGen<'a, 'b> {
+pub struct IRGen<'a, 'b, 'c> {
     bundle:         &'a mut ModuleBundle<'b>,
+    debug:          Option<DebugInfo<'c>>,
 }
 
-impl <'a, 'b> IRGen<'a, 'b> {
-    fn new(bundle: &'a mut ModuleBundle<'b>) -> Self {
-        IRGen{bundle}
+impl <'a, 'b, 'c> IRGen<'a, 'b, 'c> {
+    fn new(bundle: &'a mut ModuleBundle<'b>, debug: Option<DebugInfo<'c>>) -> Self {
+        IRGen{bundle, debug}
     }
 
-    fn gen_entry(&mut self, n: usize) -> LLVMBasicBlockRef {
-        let f_name = String::new() + self.bundle.name + "_main\0";
+    /// Adds the function attribute `name` (e.g., 'optsize') to `f`.
+    fn add_attribute(&self, f: LLVMValueRef, name: &str) -> () {
+        unsafe {
+            let kind = LLVMGetEnumAttributeKindForName(name.as_ptr() as *const c_char, name.len());
+            let attribute = LLVMCreateEnumAttribute(self.bundle.context, kind, 0);
+            LLVMAddAttributeAtIndex(f, LLVMAttributeFunctionIndex, attribute);
+        }
+    }
+
+    fn gen_entry(&mut self, name: &str, n: usize, opt_level: OptLevel) -> LLVMBasicBlockRef {
+        let f_name = ModuleBundle::value_name(name);
         let mut param_types: Vec<LLVMTypeRef> = Vec::new();
         for _ in 0..n {
             param_types.push(self.bundle.t_i64);
@@ -64,8 +85,17 @@ impl <'a, 'b> IRGen<'a, 'b> {
                 false as LLVMBool
             );
             let f = LLVMAddFunction(self.bundle.module, f_name.as_ptr() as *const c_char, f_type);
+            // The size levels are only seen by codegen through the attributes of the kernel
+            match opt_level {
+                OptLevel::Os    => self.add_attribute(f, "optsize"),
+                OptLevel::Oz    => {
+                    self.add_attribute(f, "minsize");
+                    self.add_attribute(f, "optsize");
+                },
+                _               => (),
+            };
             self.bundle.f = Some(f);
-            self.bundle.f_sig = Some(FunctionSignature::new(t_ret, param_types));
+            self.bundle.f_sig = Some(FunctionSignature::new(name, t_ret, param_types));
             let bb = LLVMAppendBasicBlockInContext(
                 self.bundle.context,
                 f,
@@ -91,7 +121,7 @@ impl <'a, 'b> IRGen<'a, 'b> {
         let value = match f {
             Factor::Number(n)   => self.bundle.get_constint(self.bundle.t_i64, *n),
             Factor::Ident(name) => {
-                let n = ModuleBundle::value_name(name);
+                let n = ModuleBundle::value_name(&ModuleBundle::mangle_name(name));
                 let alloca_value = self.bundle.get_value(&n);
                 let value_name = self.bundle.scope.next_value_name();
                 unsafe {
@@ -107,9 +137,10 @@ impl <'a, 'b> IRGen<'a, 'b> {
         Ok(value)
     }
 
-    fn gen_expr_binop(&mut self, op: &Operator, e_left: &Expr, e_right: &Expr) -> GenResult {
+    fn gen_expr_binop(&mut self, op: &Operator, e_left: &Expr, e_right: &Expr, span: Span) -> GenResult {
         let value_left = self.visit(e_left).unwrap();
         let value_right = self.visit(e_right).unwrap();
+        self.set_location(span);
         let value_name = self.bundle.scope.next_value_name();
         let value_name_c = value_name.as_ptr() as *const c_char;
         let value = match op {
@@ -134,44 +165,121 @@ impl <'a, 'b> IRGen<'a, 'b> {
     /// in the order they appear (e.g., "with a,b" maps to "foo(%0, %1)", where %0 is a and %1 is b).
     /// Note that this only works for simple single statement programs; a base offset would be
     /// needed if longer multi-statement programs are implemented.
-    fn gen_expr_withdecl(&mut self, vars: &Vars, e: &Expr) -> GenResult {
+    /// The parameters are described in the debug info at their declaration `spans` (if any).
+    fn gen_expr_withdecl(&mut self, vars: &Vars, spans: &[Span], e: &Expr) -> GenResult {
         let f = self.bundle.f.expect("Missing parent function");
         for (i, var) in vars.iter().enumerate() {
-            unsafe {
-                let alloca_value = self.bundle.gen_alloca(var.as_str(), self.bundle.t_i64);
+            let alloca_value = unsafe {
+                let alloca_value = self.bundle.gen_alloca(&ModuleBundle::mangle_name(var), self.bundle.t_i64);
                 let init_value = LLVMGetParam(f, i as c_uint);
                 let _store_value = LLVMBuildStore(self.bundle.builder, init_value, alloca_value);
+                alloca_value
             };
+            if let Some(debug) = self.debug.as_ref() {
+                let bb = unsafe { LLVMGetInsertBlock(self.bundle.builder) };
+                let span = spans.get(i).copied().unwrap_or_default();
+                debug.gen_param(self.bundle, var, i, alloca_value, bb, span);
+            }
         };
         self.visit(e)
     }
 
-    pub fn gen(ast: &dyn Ast, bundle: &'a mut ModuleBundle<'b>) -> bool {
-        let mut ir_gen = IRGen::new(bundle);
+    /// Declares the extern function `name` with the arity of `params` (all of type i64).
+    /// The function is expected to be defined by a linked module (e.g., with `--link-bitcode`)
+    /// or object file.
+    fn gen_expr_externdecl(&mut self, name: &str, params: &Vars, e: &Expr) -> GenResult {
+        let f_name = ModuleBundle::value_name(name);
+        let is_defined = unsafe { !LLVMGetNamedFunction(self.bundle.module, f_name.as_ptr() as *const c_char).is_null() };
+        if is_defined {
+            eprintln!("Extern function '{}' has the name of the kernel", name);
+            return Err("Failed to declare extern function");
+        }
+        let mut param_types: Vec<LLVMTypeRef> = vec![self.bundle.t_i64; params.len()];
+        let _f = self.bundle.emit_declaration(&f_name, self.bundle.t_i64, &mut param_types, false);
+        self.visit(e)
+    }
+
+    fn gen_expr_call(&mut self, name: &str, args: &[&Expr], span: Span) -> GenResult {
+        let mut values: Vec<LLVMValueRef> = Vec::new();
+        for arg in args {
+            values.push(self.visit(*arg)?);
+        }
+        self.set_location(span);
+        let f = self.bundle.get_value(&ModuleBundle::value_name(name));
+        let value_name = self.bundle.scope.next_value_name();
+        let value = unsafe {
+            LLVMBuildCall2(
+                self.bundle.builder,
+                LLVMGlobalGetValueType(f),
+                f,
+                values.as_mut_ptr(),
+                values.len() as c_uint,
+                value_name.as_ptr() as *const c_char
+            )
+        };
+        Ok(value)
+    }
+
+    fn set_location(&self, span: Span) -> () {
+        if let Some(debug) = self.debug.as_ref() {
+            debug.set_location(self.bundle, span);
+        }
+    }
+
+    /// Generates the kernel for `ast` into `bundle` as function `name`.
+    /// If `debug` is given, debug info is generated for the kernel and its parameters.
+    /// The kernel is marked for size optimization at `opt_level` Os (optsize) and Oz (minsize).
+    /// Returns false if the AST fails IR code generation (the error has already been reported).
+    pub fn gen(
+        ast: &dyn Ast,
+        bundle: &'a mut ModuleBundle<'b>,
+        name: &str,
+        opt_level: OptLevel,
+        debug: Option<DebugInfo<'c>>,
+    ) -> bool {
+        let mut ir_gen = IRGen::new(bundle, debug);
         let n = ast.get_vars();
-        let bb = ir_gen.gen_entry(n);
+        let bb = ir_gen.gen_entry(name, n, opt_level);
+        if let Some(debug) = ir_gen.debug.as_mut() {
+            let f = ir_gen.bundle.f.expect("Missing parent function");
+            debug.gen_subprogram(f, name, n, ast.get_span());
+        }
         let ir_gen_result: GenResult = ast.accept_gen(&mut ir_gen);
         let ir_gen_value: LLVMValueRef = match ir_gen_result {
             Ok(value)   => value,
             Err(msg)    => {
                 eprintln!("{}\nAST failed IR code generation", msg);
-                exit(ExitCode::IRGenError);
+                return false;
             },
         };
+        ir_gen.set_location(ast.get_span());
         ir_gen.gen_ret(bb, ir_gen_value);
+        if let Some(debug) = ir_gen.debug.as_ref() {
+            debug.finalize(ir_gen.bundle);
+        }
         true
     }
 }
 
-impl <'a, 'b> AstGenerator for IRGen<'a, 'b> {
+impl <'a, 'b, 'c> AstGenerator for IRGen<'a, 'b, 'c> {
     fn visit(&mut self, ast: &dyn Ast) -> GenResult {
         if ast.is_expr() {
+            self.set_location(ast.get_span());
             let expr: &ExprKind = ast.get_expr();
             let result = match expr {
                 ExprKind::Undefined                     => self.gen_expr_undefined(),
                 ExprKind::Factor(f)                     => self.gen_expr_factor(f),
-                ExprKind::BinaryOp(op, e_left, e_right) => self.gen_expr_binop(op, e_left, e_right),
-                ExprKind::WithDecl(vars, e)             => self.gen_expr_withdecl(vars, e),
+                ExprKind::BinaryOp(op, e_left, e_right) => {
+                    self.gen_expr_binop(op, e_left, e_right, ast.get_span())
+                },
+                ExprKind::WithDecl(vars, e)             => {
+                    self.gen_expr_withdecl(vars, ast.get_decl_spans(), e)
+                },
+                ExprKind::NameDecl(_, e)                => self.visit(*e),
+                ExprKind::ExternDecl(name, params, e)   => self.gen_expr_externdecl(name, params, e),
+                ExprKind::Call(name, args)              => {
+                    self.gen_expr_call(name, args, ast.get_span())
+                },
             };
             return result;
         }
@@ -0,0 +1,237 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Compile the optimized kernel module in-process with MCJIT for `--run`, and call it with the
+//! arguments given after `--` (no temporary files or external tools are needed).
+//! Since the number of kernel parameters is only known at runtime, a wrapper function taking the
+//! arguments as an array of i64 is generated into the module and called in place of the kernel.
+
+extern crate llvm_sys as llvm;
+
+use llvm::core::LLVMAddFunction;
+use llvm::core::LLVMAppendBasicBlockInContext;
+use llvm::core::LLVMBuildCall2;
+use llvm::core::LLVMBuildGEP2;
+use llvm::core::LLVMBuildLoad2;
+use llvm::core::LLVMBuildRet;
+use llvm::core::LLVMDisposeMessage;
+use llvm::core::LLVMFunctionType;
+use llvm::core::LLVMGetParam;
+use llvm::core::LLVMPositionBuilderAtEnd;
+use llvm::execution_engine::LLVMCreateMCJITCompilerForModule;
+use llvm::execution_engine::LLVMDisposeExecutionEngine;
+use llvm::execution_engine::LLVMExecutionEngineRef;
+use llvm::execution_engine::LLVMGetFunctionAddress;
+use llvm::execution_engine::LLVMInitializeMCJITCompilerOptions;
+use llvm::execution_engine::LLVMLinkInMCJIT;
+use llvm::execution_engine::LLVMMCJITCompilerOptions;
+use llvm::execution_engine::LLVMRemoveModule;
+use llvm::prelude::LLVMBool;
+use llvm::prelude::LLVMModuleRef;
+use llvm::prelude::LLVMTypeRef;
+use llvm::prelude::LLVMValueRef;
+
+use std::ffi::c_char;
+use std::ffi::c_uint;
+use std::ffi::CStr;
+use std::mem;
+use std::ptr;
+
+use crate::exit_code;
+use crate::module;
+use crate::options;
+use crate::sem;
+
+use exit_code::exit;
+use exit_code::ExitCode;
+use module::ModuleBundle;
+use options::OptLevel;
+use options::RunOptions;
+use sem::RESERVED_PREFIX;
+
+type WrapperFn = unsafe extern "C" fn(*const i64) -> i64;
+
+pub struct Jit<'a, 'b> {
+    bundle: &'a mut ModuleBundle<'b>,
+    engine: LLVMExecutionEngineRef,
+}
+
+impl <'a, 'b> Jit<'a, 'b> {
+    /// Creates an execution engine for the module in `bundle`.
+    /// The engine takes ownership of the module until it is dropped, at which point the module is
+    /// handed back to the bundle.
+    fn new(bundle: &'a mut ModuleBundle<'b>, opt_level: OptLevel) -> Option<Self> {
+        let mut engine: LLVMExecutionEngineRef = ptr::null_mut();
+        let mut error_ptr: *mut c_char = ptr::null_mut();
+        let result: LLVMBool = unsafe {
+            LLVMLinkInMCJIT();
+            let mut jit_options: LLVMMCJITCompilerOptions = mem::zeroed();
+            LLVMInitializeMCJITCompilerOptions(&mut jit_options, mem::size_of::<LLVMMCJITCompilerOptions>());
+            // The size levels use the default codegen level (as in `TargetMachine::new`)
+            jit_options.OptLevel = match opt_level {
+                OptLevel::Os | OptLevel::Oz => OptLevel::O2,
+                _                           => opt_level,
+            } as c_uint;
+            LLVMCreateMCJITCompilerForModule(
+                &mut engine as *mut LLVMExecutionEngineRef,
+                bundle.module,
+                &mut jit_options,
+                mem::size_of::<LLVMMCJITCompilerOptions>(),
+                &mut error_ptr as *mut *mut c_char,
+            )
+        };
+        if result != false as LLVMBool {
+            if !error_ptr.is_null() {
+                let c_string = unsafe { CStr::from_ptr(error_ptr as *const c_char) };
+                eprintln!("{}", c_string.to_str().expect("Unable to read JIT error string"));
+                unsafe { LLVMDisposeMessage(error_ptr); }
+            }
+            return None;
+        }
+        Some(Jit{bundle, engine})
+    }
+
+    /// Generates `i64 __calcc_run(ptr %args)`, which loads each argument of the kernel from the
+    /// array `args` and returns the result of calling the kernel.
+    fn gen_wrapper(bundle: &mut ModuleBundle, name: &str) -> bool {
+        let (f_kernel, f_sig) = match (bundle.f, bundle.f_sig.clone()) {
+            (Some(f), Some(f_sig))  => (f, f_sig),
+            _                       => {
+                eprintln!("Expected kernel function in module for JIT");
+                return false;
+            },
+        };
+        let mut param_types: Vec<LLVMTypeRef> = vec![bundle.t_opaque];
+        let f_name = ModuleBundle::value_name(name);
+        let f = unsafe {
+            let t_f = LLVMFunctionType(
+                bundle.t_i64,
+                param_types.as_mut_ptr(),
+                param_types.len() as u32,
+                false as LLVMBool
+            );
+            let f = LLVMAddFunction(bundle.module, f_name.as_ptr() as *const c_char, t_f);
+            let bb = LLVMAppendBasicBlockInContext(
+                bundle.context,
+                f,
+                ModuleBundle::value_name("entry").as_ptr() as *const c_char,
+            );
+            LLVMPositionBuilderAtEnd(bundle.builder, bb);
+            f
+        };
+        let value_args = unsafe { LLVMGetParam(f, 0) };
+        let mut args: Vec<LLVMValueRef> = Vec::new();
+        for (i, t_arg) in f_sig.params.iter().enumerate() {
+            let name_gep_tmp = bundle.scope.next_value_name();
+            let name_load_tmp = bundle.scope.next_value_name();
+            let mut indices: Vec<LLVMValueRef> = vec![bundle.get_constint(bundle.t_i64, i as i64)];
+            let value_load = unsafe {
+                let value_gep_tmp = LLVMBuildGEP2(
+                    bundle.builder,
+                    bundle.t_i64,
+                    value_args,
+                    indices.as_mut_ptr(),
+                    indices.len() as c_uint,
+                    name_gep_tmp.as_ptr() as *const c_char
+                );
+                LLVMBuildLoad2(
+                    bundle.builder,
+                    *t_arg,
+                    value_gep_tmp,
+                    name_load_tmp.as_ptr() as *const c_char
+                )
+            };
+            args.push(value_load);
+        }
+        let name_call_tmp = bundle.scope.next_value_name();
+        unsafe {
+            let t_f = LLVMFunctionType(
+                f_sig.t_ret,
+                f_sig.params.clone().as_mut_ptr(),
+                f_sig.params.len() as u32,
+                false as LLVMBool
+            );
+            let value_call_tmp = LLVMBuildCall2(
+                bundle.builder,
+                t_f,
+                f_kernel,
+                args.as_mut_ptr(),
+                args.len() as c_uint,
+                name_call_tmp.as_ptr() as *const c_char
+            );
+            LLVMBuildRet(bundle.builder, value_call_tmp);
+        }
+        true
+    }
+
+    fn get_function(&self, name: &str) -> Option<WrapperFn> {
+        let f_name = ModuleBundle::value_name(name);
+        let address: u64 = unsafe { LLVMGetFunctionAddress(self.engine, f_name.as_ptr() as *const c_char) };
+        if address == 0 {
+            eprintln!("Failed to find function '{}' in JIT", name);
+            return None;
+        }
+        Some(unsafe { mem::transmute::<usize, WrapperFn>(address as usize) })
+    }
+
+    /// Compiles the kernel in `bundle`, calls it with `args`, and returns the result.
+    pub fn call(bundle: &'a mut ModuleBundle<'b>, args: &[i64], options: &RunOptions) -> Option<i64> {
+        let (n, name) = match bundle.f_sig {
+            Some(ref f_sig) => (f_sig.params.len(), format!("{}run", RESERVED_PREFIX)),
+            None            => {
+                eprintln!("Expected kernel function in module for JIT");
+                return None;
+            },
+        };
+        if args.len() != n {
+            eprintln!("Invalid number of args to main. Expected {} args", n);
+            return None;
+        }
+        if !Self::gen_wrapper(bundle, &name) || !bundle.verify_module() {
+            eprintln!("Failed to generate JIT wrapper for kernel");
+            return None;
+        }
+        let jit = match Self::new(bundle, options.opt_level) {
+            Some(jit)   => jit,
+            None        => {
+                eprintln!("Failed to create JIT for module");
+                return None;
+            },
+        };
+        let f = jit.get_function(&name)?;
+        if options.verbose {
+            eprintln!("Calling '{}' with args {:?}", name, args);
+        }
+        let result: i64 = unsafe { f(args.as_ptr()) };
+        drop(jit);
+        Some(result)
+    }
+
+    /// Compiles the kernel in `bundle`, calls it with `args`, and prints the result.
+    pub fn run(bundle: &'a mut ModuleBundle<'b>, args: &[i64], options: &RunOptions) -> ! {
+        match Self::call(bundle, args, options) {
+            Some(result)    => println!("calcc_main result: {}", result),
+            None            => exit(ExitCode::JitError),
+        };
+        exit(ExitCode::Ok);
+    }
+}
+
+impl <'a, 'b> Drop for Jit<'a, 'b> {
+    fn drop(&mut self) -> () {
+        let mut module: LLVMModuleRef = ptr::null_mut();
+        let mut error_ptr: *mut c_char = ptr::null_mut();
+        unsafe {
+            LLVMRemoveModule(
+                self.engine,
+                self.bundle.module,
+                &mut module as *mut LLVMModuleRef,
+                &mut error_ptr as *mut *mut c_char,
+            );
+            if !error_ptr.is_null() {
+                LLVMDisposeMessage(error_ptr);
+            }
+            LLVMDisposeExecutionEngine(self.engine);
+        }
+    }
+}
@@ -1,10 +1,6 @@
 // Copyright 2024, Giordano Salvador
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::fmt;
-use std::fmt::Display;
-use std::io::BufRead;
-use std::io::BufReader;
 use std::io::Read;
 
 use crate::exit_code;
@@ -54,21 +50,32 @@ pub fn token_kind_to_string(k: TokenKind) -> String {
     }.to_string()
 }
 
-#[derive(Clone)]
-pub struct Token {
-    pub kind: TokenKind,
-    pub text: String,
+/// Byte offsets `[start, end)` into the source buffer held by the lexer.
+#[derive(Clone,Copy,Default,Eq,PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
-impl Default for Token {
-    fn default() -> Self {
-        Token::new(TokenKind::Unknown, Default::default())
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span{start, end}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
     }
 }
 
+#[derive(Clone,Copy,Default)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
 impl Token {
-    pub fn new(k: TokenKind, text: String) -> Self {
-        Token{kind: k, text}
+    pub fn new(k: TokenKind, span: Span) -> Self {
+        Token{kind: k, span}
     }
 
     pub fn is(&self, k: TokenKind) -> bool {
@@ -86,111 +93,139 @@ impl Token {
         }
         f(self, false, ks)
     }
+
+    /// Returns the slice of the source buffer covered by the token.
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.span.start..self.span.end]
+    }
+
+    pub fn to_string_with(self, source: &str) -> String {
+        format!("{}:{}", token_kind_to_string(self.kind), self.text(source))
+    }
 }
 
-impl Display for Token {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}", token_kind_to_string(self.kind), self.text)
+/// Reads the whole input into a single buffer to be borrowed by the lexer.
+pub fn read_source<T: Read>(mut readable: T) -> String {
+    let mut bytes: Vec<u8> = Vec::new();
+    if let Err(msg) = readable.read_to_end(&mut bytes) {
+        eprintln!("Failed to read input: {}", msg);
+        exit(ExitCode::LexerError);
+    }
+    match String::from_utf8(bytes) {
+        Ok(source)  => source,
+        Err(msg)    => {
+            eprintln!("Input is not valid UTF-8: {}", msg);
+            exit(ExitCode::LexerError);
+        },
     }
 }
 
-pub struct Lexer<'a, T: Read> {
-    buffer: BufReader<T>,
-    line: String,
+pub struct Lexer<'a> {
+    source: &'a str,
+    bytes: &'a [u8],
     line_count: usize,
+    line_start: usize,
+    line_end: usize,
+    line_is_ascii: bool,
     position: usize,
+    done: bool,
+    failed: bool,
     options: &'a RunOptions,
 }
 
-impl <'a, T: Read> Lexer<'a, T> {
-    pub fn new(readable: T, options: &'a RunOptions) -> Self {
+impl <'a> Lexer<'a> {
+    pub fn new(source: &'a str, options: &'a RunOptions) -> Self {
         Lexer{
-            buffer: BufReader::new(readable),
-            line: String::new(),
+            source,
+            bytes: source.as_bytes(),
             line_count: 0,
+            line_start: 0,
+            line_end: 0,
+            line_is_ascii: true,
             position: 0,
+            done: false,
+            failed: false,
             options,
         }
     }
 
+    /// Advances to the next line of the buffer if the current line has been consumed.
     fn has_next(&mut self) -> bool {
-        if self.has_next_in_line(self.position) { 
-            true
-        } else {
-            if !self.line.is_empty() && self.position >= self.line.len() {
-                self.line = Default::default();
-            }
-            match self.buffer.read_line(&mut self.line) {
-                Ok(size) => {
-                    if size > 0 {
-                        if self.options.verbose {
-                            eprintln!("Read {} bytes from buffer at line {}", size, self.line_count);
-                        }
-                        self.line_count += 1;
-                        self.position = 0;
-                        true
-                    } else {
-                        false
-                    }
-                }
-                Err(_) => false,
-            }
+        if self.has_next_in_line(self.position) {
+            return true;
+        }
+        if self.line_end >= self.bytes.len() {
+            return false;
         }
+        self.line_start = self.line_end;
+        self.line_end = match self.bytes[self.line_start..].iter().position(|b| *b == b'\n') {
+            Some(i) => self.line_start + i + 1,
+            None    => self.bytes.len(),
+        };
+        self.line_is_ascii = self.bytes[self.line_start..self.line_end].is_ascii();
+        if self.options.verbose {
+            eprintln!(
+                "Read {} bytes from buffer at line {}",
+                self.line_end - self.line_start,
+                self.line_count
+            );
+        }
+        self.line_count += 1;
+        self.position = self.line_start;
+        true
     }
 
     fn has_next_in_line(&self, pos: usize) -> bool {
-        if self.line.is_empty() {
-            false
+        pos < self.line_end
+    }
+
+    /// Returns the column (in characters, not bytes) of the byte offset `pos` in the current line.
+    fn column(&self, pos: usize) -> usize {
+        if self.line_is_ascii {
+            pos - self.line_start
         } else {
-            pos < self.line.len()
+            self.source[self.line_start..pos].chars().count()
         }
     }
 
-    fn next_char_in_line(&self, pos: usize) -> char {
-        let c_opt = self.line.get(pos..pos + 1);
-        match c_opt {
-            None            => {
-                eprintln!("Expected char in line {} at pos {}", self.line_count - 1, pos);
-                exit(ExitCode::LexerError);
-            }
-            Some(c_slice)   => {
-                let c: char = c_slice.chars().next().unwrap();
-                if !c.is_ascii() {
-                    eprintln!("Only ASCII characters are supported by the lexer");
-                    exit(ExitCode::LexerError);
-                }
-                if self.options.verbose {
-                    eprintln!("Found char '{}' in line {} at pos {}", c, self.line_count - 1, pos);
-                }
-                c
-            }
+    fn next_char_in_line(&mut self, pos: usize) -> char {
+        let c: char = match self.source.get(pos..).and_then(|s| s.chars().next()) {
+            Some(c) => c,
+            None    => {
+                eprintln!("Expected char in line {} at pos {}", self.line_count - 1, self.column(pos));
+                self.failed = true;
+                return '\0';
+            },
+        };
+        if self.options.verbose {
+            eprintln!("Found char '{}' in line {} at pos {}", c, self.line_count - 1, self.column(pos));
         }
+        c
     }
 
-    fn collect_token_sequence(&self, pos: usize, pred: fn(char) -> bool) -> usize {
+    fn collect_token_sequence(&mut self, pos: usize, pred: fn(char) -> bool) -> usize {
         let mut pos_end: usize = pos;
-        let mut c: char;
         while self.has_next_in_line(pos_end) {
-            c = self.next_char_in_line(pos_end);
-            if !pred(c) {
+            let c: char = self.next_char_in_line(pos_end);
+            if self.failed || !pred(c) {
                 break
             }
-            pos_end += 1;
+            pos_end += c.len_utf8();
         }
         pos_end
     }
 
-    fn check_suffix(&self, pos: usize) -> () {
+    fn check_suffix(&mut self, pos: usize) -> () {
         if self.has_next_in_line(pos) {
             let c: char = self.next_char_in_line(pos);
-            if !Self::is_whitespace(c) && !Self::is_other(c) {
+            if !self.failed && !Self::is_whitespace(c) && !Self::is_other(c) {
                 eprintln!("Found invalid suffix '{}' for number in expression", c);
-                exit(ExitCode::LexerError);
+                self.failed = true;
             }
         }
     }
 
-    fn next_in_line(&mut self, t: &mut Token) -> () {
+    fn next_in_line(&mut self) -> Token {
         let (mut c, mut pos_start): (char, usize) = ('\0', self.position);
         while self.has_next_in_line(pos_start) {
             c = self.next_char_in_line(pos_start);
@@ -198,42 +233,40 @@ impl <'a, T: Read> Lexer<'a, T> {
             pos_start += 1;
         }
         if Self::is_whitespace(c) {
-            self.form_token(t, pos_start, pos_start + 1, TokenKind::Eol);
+            self.form_token(pos_start, pos_start + 1, TokenKind::Eol)
         } else if Self::is_digit(c) {
             if c == '0' && self.has_next_in_line(pos_start + 1) {
                 c = self.next_char_in_line(pos_start + 1);
                 if c == 'x' {
                     let pos_end: usize = self.collect_token_sequence(pos_start + 2, Self::is_hex_digit);
                     self.check_suffix(pos_end);
-                    self.form_token(t, pos_start, pos_end, TokenKind::Number);
-                    return;
+                    return self.form_token(pos_start, pos_end, TokenKind::Number);
                 }
             }
             let pos_end: usize = self.collect_token_sequence(pos_start + 1, Self::is_digit);
             self.check_suffix(pos_end);
-            self.form_token(t, pos_start, pos_end, TokenKind::Number);
-        } else if Self::is_letter(c) {
-            let pos_end: usize = self.collect_token_sequence(pos_start + 1, Self::is_ident);
-            let text = String::from(&self.line[pos_start..pos_end]);
-            self.form_token(
-                t,
-                pos_start,
-                pos_end,
-                if text == "with" {TokenKind::With} else {TokenKind::Ident}
-            );
+            self.form_token(pos_start, pos_end, TokenKind::Number)
+        } else if self.is_ident_start(c) {
+            let is_ident: fn(char) -> bool = if self.options.unicode_idents {
+                Self::is_ident_unicode
+            } else {
+                Self::is_ident
+            };
+            let pos_end: usize = self.collect_token_sequence(pos_start + c.len_utf8(), is_ident);
+            let k = if &self.source[pos_start..pos_end] == "with" {TokenKind::With} else {TokenKind::Ident};
+            self.form_token(pos_start, pos_end, k)
         } else if Self::is_slash(c) {
             if self.has_next_in_line(pos_start + 1) {
                 c = self.next_char_in_line(pos_start + 1);
                 if Self::is_slash(c) {
                     // It's a comment => consume the rest of the line
                     let pos_end: usize = self.collect_token_sequence(pos_start + 2, Self::is_any);
-                    self.form_token(t, pos_start, pos_end, TokenKind::Comment);
-                    return;
+                    return self.form_token(pos_start, pos_end, TokenKind::Comment);
                 }
             }
-            self.form_token(t, pos_start, pos_start + 1, TokenKind::Slash);
+            self.form_token(pos_start, pos_start + 1, TokenKind::Slash)
         } else {
-            self.form_token(t, pos_start, pos_start + 1, match c {
+            self.form_token(pos_start, pos_start + c.len_utf8(), match c {
                 ',' => TokenKind::Comma,
                 ':' => TokenKind::Colon,
                 '-' => TokenKind::Minus,
@@ -247,26 +280,14 @@ impl <'a, T: Read> Lexer<'a, T> {
         }
     }
 
-    pub fn next(&mut self, t: &mut Token) -> () {
-        let mut t_tmp: Token = Default::default();
-        if self.has_next() {
-            self.next_in_line(&mut t_tmp);
-        } else {
-            t_tmp.kind = TokenKind::Eoi;
-        }
-        std::mem::swap(t, &mut t_tmp);
-    }
-
-    fn form_token(&mut self, t: &mut Token, pos_start: usize, pos_end: usize, k: TokenKind) -> () {
-        t.kind = k;
-        t.text = String::from(
-            if k == TokenKind::Eoi || k == TokenKind::Eol {
-                ""
-            } else {
-                &self.line[pos_start..pos_end]
-            }
-        );
+    fn form_token(&mut self, pos_start: usize, pos_end: usize, k: TokenKind) -> Token {
         self.position = pos_end;
+        let span = if k == TokenKind::Eoi || k == TokenKind::Eol {
+            Span::new(pos_start, pos_start)
+        } else {
+            Span::new(pos_start, pos_end)
+        };
+        Token::new(k, span)
     }
 
     fn is_any(_c: char) -> bool {
@@ -309,23 +330,89 @@ impl <'a, T: Read> Lexer<'a, T> {
         Self::is_letter_lower(c) || Self::is_letter_upper(c) || c == '_'
     }
 
-    pub fn lex_input(ts: &mut Vec<Token>, lex: &mut Lexer<'a, T>, options: &RunOptions) -> () {
-        let mut t: Token = Default::default();
-        while !t.is(TokenKind::Eoi) {
-            lex.next(&mut t);
+    /// Approximates Unicode `XID_Start` using the `Alphabetic` property.
+    fn is_letter_unicode(c: char) -> bool {
+        Self::is_letter(c) || c.is_alphabetic()
+    }
+
+    /// Approximates Unicode `XID_Continue` using the `Alphabetic` and `Numeric` properties.
+    fn is_ident_unicode(c: char) -> bool {
+        Self::is_ident(c) || c.is_alphanumeric()
+    }
+
+    fn is_ident_start(&self, c: char) -> bool {
+        if self.options.unicode_idents {
+            Self::is_letter_unicode(c)
+        } else {
+            Self::is_letter(c)
+        }
+    }
+
+    /// Returns true if `text` would be lexed as a single `Ident` token.
+    pub fn is_ident_text(text: &str, options: &RunOptions) -> bool {
+        let is_ident_start: fn(char) -> bool = if options.unicode_idents {
+            Self::is_letter_unicode
+        } else {
+            Self::is_letter
+        };
+        let is_ident: fn(char) -> bool = if options.unicode_idents {
+            Self::is_ident_unicode
+        } else {
+            Self::is_ident
+        };
+        let mut chars = text.chars();
+        match chars.next() {
+            Some(c) => is_ident_start(c) && chars.all(is_ident) && text != "with",
+            None    => false,
+        }
+    }
+
+    /// Collects the tokens for the parser into `ts`.
+    /// Comments are kept out of the parser's token stream and collected into `comments` instead.
+    /// Returns an error after the first invalid token (the error has already been reported).
+    pub fn lex_input(
+        ts: &mut Vec<Token>,
+        comments: &mut Vec<Token>,
+        lex: &mut Lexer<'a>,
+        options: &RunOptions
+    ) -> Result<(), ExitCode> {
+        let source: &str = lex.source;
+        for t in lex.by_ref() {
             if t.is(TokenKind::Unknown) {
-                eprintln!("Found unknown token '{}' in lexer", t.text);
-                if !options.drop_token { exit(ExitCode::LexerError); }
+                eprintln!("Found unknown token '{}' in lexer", t.text(source));
+                if !options.drop_token { return Err(ExitCode::LexerError); }
             } else if options.verbose {
-                eprintln!("Lexed token '{}'", t);
+                eprintln!("Lexed token '{}'", t.to_string_with(source));
             }
-            if t.is(TokenKind::Comment) || t.is(TokenKind::Eol) {
-                // Drop the comments and end of lines before parsing
+            if t.is(TokenKind::Comment) {
+                comments.push(t);
+                continue;
+            } else if t.is(TokenKind::Eol) {
+                // Drop the end of lines before parsing
                 continue;
             }
-            ts.push(t.clone());
+            ts.push(t);
+        }
+        if lex.failed {
+            return Err(ExitCode::LexerError);
         }
+        Ok(())
+    }
+}
 
-        if options.lex_exit { exit(ExitCode::Ok); }
+impl <'a> Iterator for Lexer<'a> {
+    type Item = Token;
+
+    /// Yields tokens up to and including the final `Eoi` token (or up to an invalid number).
+    fn next(&mut self) -> Option<Token> {
+        if self.done || self.failed {
+            None
+        } else if self.has_next() {
+            Some(self.next_in_line())
+        } else {
+            self.done = true;
+            let end = self.bytes.len();
+            Some(Token::new(TokenKind::Eoi, Span::new(end, end)))
+        }
     }
 }
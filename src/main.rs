@@ -10,73 +10,146 @@ const VERSION: &str = "v0.2.0";
 use std::env;
 use std::fmt;
 use std::fs::File;
-use std::io::Cursor;
+use std::io::Write;
 use std::io::stdin;
 use std::path::Path;
 use std::process;
 
+mod archive;
 mod ast;
 mod command;
+mod debug;
+mod deserialize;
+mod eval;
 mod exit_code;
+mod fold;
+mod header;
 mod irgen;
+mod jit;
 mod lex;
 mod maingen;
 mod maingen_c;
 mod module;
 mod parse;
 mod options;
+mod pretty;
+mod repl;
+mod rust_bindings;
 mod sem;
+mod serialize;
 mod target;
 
+use archive::ArchiveMember;
+use archive::ArchiveWriter;
+use ast::get_decl_vars;
+use ast::get_name_decl;
 use ast::Ast;
 use ast::Expr;
+use ast::Vars;
+use debug::DebugInfo;
+use deserialize::AstReader;
+use eval::Interpreter;
 use exit_code::exit;
 use exit_code::ExitCode;
+use fold::ConstFold;
+use header::HeaderGen;
 use irgen::IRGen;
+use jit::Jit;
+use lex::read_source;
 use lex::Lexer;
 use lex::Token;
 use maingen::MainGen;
 use maingen_c::MainGenC;
+use module::FunctionSignature;
 use module::ModuleBundle;
+use options::AstFormat;
 use options::BodyType;
 use options::CodeGenType;
 use options::HostOS;
+use options::InputFormat;
 use options::OptLevel;
 use options::OutputType;
 use options::RunOptions;
 use parse::Parser;
+use pretty::PrettyPrinter;
+use repl::Repl;
+use rust_bindings::RustBindingsGen;
 use sem::Semantics;
+use serialize::AstSerializer;
 use target::Passes;
 use target::PassBuilder;
 use target::Target;
 use target::TargetMachine;
 
 fn help(code: ExitCode) -> ! {
-    eprintln!("usage: {} [OPTIONS] <INPUT>\n{}", PACKAGE, [
-        "INPUT              '-' (i.e., Stdin) or a file path",
+    eprintln!("usage: {} [OPTIONS] <INPUT> [-- <ARGS>...]\n       {} --archive [OPTIONS] -o <F> <INPUT>...\n       {} fmt [--check] [OPTIONS] <INPUT>\n       {} repl [OPTIONS]\n{}", PACKAGE, PACKAGE, PACKAGE, PACKAGE, [
+        "INPUT              '-' (i.e., Stdin) or a file path (several INPUTs are compiled to one kernel each)",
+        "                   With several INPUTs, the first argument of the executable names the kernel to run",
+        "                   INPUTs with a '.o' or '.a' extension are linked into the executable or shared library",
+        "                   (and are read as calc source otherwise)",
+        "ARGS               Integer arguments for the parameters of INPUT (requires --eval or --run)",
+        "fmt                Print INPUT as canonically formatted calc source (must be the first argument)",
+        "repl               Evaluate calc expressions read line by line from Stdin (must be the first argument)",
+        "                   Enter ':help' in the REPL for the list of commands",
         "OPTIONS:",
+        "--archive          Output a static archive of the kernels of all INPUTs (.a if used with -o)",
+        "                   Each kernel is named by 'name:' in its INPUT or else by the file stem of INPUT",
+        "--asm-source       With -s|--asm, annotate the assembly with the calc source line of each instruction",
+        "                   (also emits the DWARF line table, i.e., '.debug_*' sections, without -g)",
         "--ast              Print the AST after parsing",
         "-b|--bitcode       Output LLVM bitcode (post-optimization) (.bc if used with -o)",
         "-c                 Output an object file (post-optimization) (.o if used with -o)",
+        "--check            With fmt, exit with an error if INPUT is not already formatted",
+        "--cpu[=]<C>        Generate code for CPU C of the target ('native' for the host CPU and features)",
+        "--debug-passes     Print each pass run by the pass manager (to Stderr)",
         "--drop             Drop unknown tokens instead of failing",
         "-e|--expr[=]<E>    Process expression E instead of INPUT file",
+        "--emit-ast[=]<F>   Output the AST in format F (json|sexpr|dot) after the semantics check and exit",
+        "--emit-header[=]<F>",
+        "                   Also write a C header file F declaring the kernel(s) (e.g., for use with -k|--no-main or --archive)",
+        "--emit-rust-bindings[=]<F>",
+        "                   Also write Rust bindings (extern block and safe wrapper) for the kernel(s) to file F",
+        "--entry-name[=]<S> Name the kernel function S (overrides 'name:' in INPUT) (default: calcc_main)",
+        "--eval             Evaluate INPUT with ARGS using the interpreter and print the result",
+        "--features[=]<F>   Enable/disable the target features in F (e.g., '+avx2,-sse4a')",
+        "-g                 Generate debug info (DWARF) mapping the code to the calc source",
         "-h|--help          Print this list of command line options",
+        "--input-format[=]<F>",
+        "                   Read INPUT in format F (source|ast-json) (default: source)",
+        "-l<L>              Link the library L into the executable or shared library (e.g., '-lm')",
+        "-L<D>              Add directory D (with no space after '-L') to the library search path when linking",
         "--lex              Exit after running the lexer",
         "--ir               Exit after printing IR (pre-optimization)",
+        "--link-arg[=]<A>   Pass argument A to clang when linking the executable or shared library",
+        "--link-bitcode[=]<F>",
+        "                   Link the LLVM bitcode file F into the kernel module (e.g., to define 'extern' functions)",
         "-S|--llvmir        Output LLVM IR (post-optimization) (.ll if used with -o)",
+        "-march=<C>         Same as --cpu=<C> (e.g., '-march=native')",
         "-k|--no-main       Omit linking with main module (i.e., output kernel only)",
         "                   When this option is selected, an executable cannot be generated",
         "--notarget         Omit target specific configuration in LLVM IR/bitcode",
+        "--no-unroll        Disable loop unrolling in the optimization pipeline",
+        "--no-vectorize     Disable loop vectorization in the optimization pipeline",
         "-o[=]<F>           Output to file F instead of Stdout ('-' for Stdout)",
-        "                   If no known extension is used (.bc|.exe|.ll|.o) an executable is assumed",
-        "                   An executable requires llc and clang to be installed",
-        "-O<0|1|2|3>        Set the optimization level (default: O2)",
+        "                   If no known extension is used (.a|.bc|.exe|.ll|.o|.s|.so) an executable is assumed",
+        "                   An executable requires clang to be installed",
+        "-O<0|1|2|3|s|z>    Set the optimization level (default: O2)",
+        "                   Os and Oz optimize for code size",
         "--parse            Exit after running the parser",
-        "--sem              Exit after running the semantics check",
+        "--passes[=]<P>     Run the pass pipeline P (new pass manager syntax) instead of 'default<O<n>>'",
+        "                   (e.g., '--passes=function(instcombine,simplifycfg)')",
+        "--run              Compile INPUT in-process (JIT), call it with ARGS, and print the result",
+        "-s|--asm           Output assembly (post-optimization) (.s if used with -o)",
+        "--sem              Exit after running the semantics checks (including constant folding)",
+        "--shared           Output a shared library exporting the kernel (.so if used with -o)",
+        "                   A shared library requires clang to be installed",
         "-C|--c-main        Link with a C-derived main module (src/main.c.template)",
         "                   This option is required for generating object files and executables on MacOS",
         "                   and requires clang to be installed",
+        "--target[=]<T>     Generate code for target triple T (default: the host target triple)",
+        "--unicode-idents   Accept Unicode letters and digits in identifiers",
         "-v|--verbose       Enable verbose output",
+        "--verify-each      Verify the module after each pass in the optimization pipeline",
         "--version          Display the package version and license information",
     ].join("\n"));
     exit(code);
@@ -84,7 +157,6 @@ fn help(code: ExitCode) -> ! {
 
 #[derive(Clone,Copy,PartialEq)]
 enum InputType<'a> {
-    None,
     Stdin,
     Expr(&'a str),
     File(&'a str),
@@ -96,7 +168,6 @@ impl <'a> fmt::Display for InputType<'a> {
             InputType::Stdin    => "Stdin".to_string(),
             InputType::Expr(e)  => format!("Expression:{}", e),
             InputType::File(f)  => format!("File:{}", f),
-            InputType::None     => "None".to_string(),
         };
         write!(f, "{}", s)
     }
@@ -127,20 +198,26 @@ fn set_body_type(options: &mut RunOptions, body_type: BodyType) -> () {
 #[derive(Clone,Copy)]
 enum ExtType {
     None,
+    A,
     BC,
     Exe,
     LL,
     O,
+    S,
+    SO,
 }
 
 fn get_extension_from_filename(name: &str) -> ExtType {
     match Path::new(name).extension() {
         None        => ExtType::None,
         Some(ext)   => match ext.to_str().unwrap() {
+            "a"     => ExtType::A,
             "bc"    => ExtType::BC,
             "exe"   => ExtType::Exe,
             "ll"    => ExtType::LL,
             "o"     => ExtType::O,
+            "s"     => ExtType::S,
+            "so"    => ExtType::SO,
             _       => ExtType::None,
         },
     }
@@ -148,19 +225,26 @@ fn get_extension_from_filename(name: &str) -> ExtType {
 
 /// Checks to ensure valid combination for BodyType, CodeGenType, and OutputType
 fn check_options_configuration(options: &RunOptions, output: &OutputType) -> () {
+    // The formatter, AST serializer, and interpreter only output text, so none of the codegen options apply
+    if options.no_codegen() {
+        return;
+    }
+
+    // The JIT runs the kernel in-process and only prints its result
+    if options.run_mode {
+        return;
+    }
+
     match *output {
-        OutputType::Stdout  => if !options.early_exit() {
-            match options.codegen_type {
-                CodeGenType::Object     => {
-                    eprintln!("Output to Stdout not supported for object files");
-                    exit(ExitCode::ArgParseError);
-                },
-                CodeGenType::Executable => {
-                    eprintln!("Output to Stdout not supported for executable files");
-                    exit(ExitCode::ArgParseError);
-                },
-                _                       => (),
-            }
+        OutputType::Stdout  => if !options.early_exit() && options.codegen_type == CodeGenType::Executable {
+            eprintln!("Output to Stdout not supported for executable files");
+            exit(ExitCode::ArgParseError);
+        } else if !options.early_exit() && options.codegen_type == CodeGenType::SharedLib {
+            eprintln!("Output to Stdout not supported for shared libraries");
+            exit(ExitCode::ArgParseError);
+        } else if !options.early_exit() && options.codegen_type == CodeGenType::Archive {
+            eprintln!("Output to Stdout not supported for archives");
+            exit(ExitCode::ArgParseError);
         },
         OutputType::File(f) => {
             let t = options.codegen_type;
@@ -172,6 +256,10 @@ fn check_options_configuration(options: &RunOptions, output: &OutputType) -> ()
                     );
                     exit(ExitCode::ArgParseError);
                 },
+                ExtType::A      => if t != CodeGenType::Archive {
+                    eprintln!("Output name ('.a' extension) should match codegen type (--archive)");
+                    exit(ExitCode::ArgParseError);
+                },
                 ExtType::BC     => if t != CodeGenType::Bitcode {
                     eprintln!("Output name ('.bc' extension) should match codegen type (-b|--bitcode)");
                     exit(ExitCode::ArgParseError);
@@ -188,6 +276,14 @@ fn check_options_configuration(options: &RunOptions, output: &OutputType) -> ()
                     eprintln!("Output name ('.o' extension) should match codegen type (-c)");
                     exit(ExitCode::ArgParseError);
                 },
+                ExtType::S      => if t != CodeGenType::Assembly {
+                    eprintln!("Output name ('.s' extension) should match codegen type (-s|--asm)");
+                    exit(ExitCode::ArgParseError);
+                },
+                ExtType::SO     => if t != CodeGenType::SharedLib {
+                    eprintln!("Output name ('.so' extension) should match codegen type (--shared)");
+                    exit(ExitCode::ArgParseError);
+                },
             }
         },
     };
@@ -202,7 +298,7 @@ fn check_options_configuration(options: &RunOptions, output: &OutputType) -> ()
         exit(ExitCode::ArgParseError);
     }
 
-    if options.host_os == HostOS::MacOS && options.body_type == BodyType::MainGen {
+    if options.get_target_os() == HostOS::MacOS && options.body_type == BodyType::MainGen {
         eprintln!("Linking the C standard library from a kernel+main module is not supported on MacOS");
         eprintln!("Please use the C-derived main option (-C|--c-main)");
         exit(ExitCode::ArgParseError);
@@ -211,50 +307,271 @@ fn check_options_configuration(options: &RunOptions, output: &OutputType) -> ()
 
 fn parse_args<'a>(
     args: &'a [String],
-    input: &mut InputType<'a>,
+    inputs: &mut Vec<InputType<'a>>,
     output: &mut OutputType<'a>,
+    kernel_args: &mut Vec<i64>,
     options: &mut RunOptions
 ) {
     let _bin_name: &String = args.first().unwrap();
     let mut arg: &'a String;
     let mut i: usize = 1;
+    let mut link_flags: Vec<&'a String> = Vec::new();
 
     while i < args.len() {
         arg = args.get(i).unwrap();
         match arg.as_str() {
+            "--"            => {
+                parse_kernel_args(&args[i + 1..], kernel_args);
+                break;
+            },
+            "--archive"     => set_codegen_type(options, CodeGenType::Archive),
+            "--asm"         => set_codegen_type(options, CodeGenType::Assembly),
+            "--asm-source"  => options.asm_source = true,
             "--ast"         => options.print_ast = true,
             "-b"            => set_codegen_type(options, CodeGenType::Bitcode),
             "--bitcode"     => set_codegen_type(options, CodeGenType::Bitcode),
             "-c"            => set_codegen_type(options, CodeGenType::Object),
             "-C"            => set_body_type(options, BodyType::MainGenC),
+            "--check"       => options.fmt_check = true,
+            "--cpu"         => options.target_cpu = parse_arg_after(args, &mut i).to_string(),
+            "--debug-passes" => options.debug_passes = true,
             "--drop"        => options.drop_token = true,
-            "-e"            => *input = InputType::Expr(parse_arg_after(args, &mut i)),
-            "--expr"        => *input = InputType::Expr(parse_arg_after(args, &mut i)),
+            "--eval"        => options.eval_mode = true,
+            "--emit-ast"    => options.ast_format = parse_ast_format(parse_arg_after(args, &mut i)),
+            "--emit-header" => options.header_file = parse_arg_after(args, &mut i).to_string(),
+            "--emit-rust-bindings" => options.rust_bindings_file = parse_arg_after(args, &mut i).to_string(),
+            "--entry-name"  => options.entry_name = parse_arg_after(args, &mut i).to_string(),
+            "-e"            => inputs.push(InputType::Expr(parse_arg_after(args, &mut i))),
+            "--expr"        => inputs.push(InputType::Expr(parse_arg_after(args, &mut i))),
+            "--features"    => options.target_features = parse_features(parse_arg_after(args, &mut i)),
+            "fmt" if i == 1 => options.fmt_mode = true,
+            "-g"            => options.debug_info = true,
+            "repl" if i == 1 => options.repl_mode = true,
             "-h"            => help(ExitCode::Ok),
             "--help"        => help(ExitCode::Ok),
+            "--input-format" => options.input_format = parse_input_format(parse_arg_after(args, &mut i)),
             "--ir"          => options.ir_exit = true,
             "-k"            => set_body_type(options, BodyType::NoMain),
             "--lex"         => options.lex_exit = true,
+            "--link-arg"    => options.link_args.push(parse_arg_after(args, &mut i).to_string()),
+            "--link-bitcode" => options.link_bitcode_files.push(parse_arg_after(args, &mut i).to_string()),
             "--llvmir"      => set_codegen_type(options, CodeGenType::Llvmir),
             "--no-main"     => set_body_type(options, BodyType::NoMain),
             "--notarget"    => options.no_target = true,
+            "--no-unroll"   => options.no_unroll = true,
+            "--no-vectorize" => options.no_vectorize = true,
             "-o"            => *output = OutputType::new(parse_arg_after(args, &mut i)),
             "-O0"           => options.opt_level = OptLevel::O0,
             "-O1"           => options.opt_level = OptLevel::O1,
             "-O2"           => options.opt_level = OptLevel::O2,
             "-O3"           => options.opt_level = OptLevel::O3,
+            "-Os"           => options.opt_level = OptLevel::Os,
+            "-Oz"           => options.opt_level = OptLevel::Oz,
             "--parse"       => options.parse_exit = true,
+            "--passes"      => options.passes = parse_passes(parse_arg_after(args, &mut i)),
+            "--run"         => options.run_mode = true,
+            "-s"            => set_codegen_type(options, CodeGenType::Assembly),
             "--sem"         => options.sem_exit = true,
+            "--shared"      => set_codegen_type(options, CodeGenType::SharedLib),
             "-S"            => set_codegen_type(options, CodeGenType::Llvmir),
             "--c-main"      => set_body_type(options, BodyType::MainGenC),
+            "--target"      => options.target_triple = parse_arg_after(args, &mut i).to_string(),
+            "--unicode-idents" => options.unicode_idents = true,
             "-v"            => options.verbose = true,
             "--verbose"     => options.verbose = true,
+            "--verify-each" => options.verify_each = true,
             "--version"     => print_pkg_info(true),
-            _               => parse_arg_complex(arg, input, output),
+            _               => parse_arg_complex(arg, inputs, output, &mut link_flags, options),
         }
         i += 1;
     }
 
+    // Only accept objects and libraries when linking, so that a mistyped option (e.g., '-llvmir')
+    // is reported and a calc INPUT can have a '.o' or '.a' extension otherwise
+    let is_linked = matches!(
+        options.codegen_type,
+        CodeGenType::Unset | CodeGenType::Executable | CodeGenType::SharedLib
+    ) && !options.early_exit() && !options.run_mode;
+    if is_linked {
+        inputs.retain(|input| match *input {
+            InputType::File(f) if matches!(get_extension_from_filename(f), ExtType::A | ExtType::O) => {
+                options.link_objects.push(f.to_string());
+                false
+            },
+            _ => true,
+        });
+    } else if let Some(flag) = link_flags.first() {
+        eprintln!(
+            "Unrecognized argument '{}' (options '-l' and '-L' are only supported for executables and shared libraries)",
+            flag
+        );
+        help(ExitCode::ArgParseError);
+    }
+
+    if options.fmt_check && !options.fmt_mode {
+        eprintln!("Option '--check' is only supported by the 'fmt' subcommand");
+        help(ExitCode::ArgParseError);
+    }
+
+    if options.fmt_mode && options.ast_format != AstFormat::Unset {
+        eprintln!("Option '--emit-ast' is not supported by the 'fmt' subcommand");
+        help(ExitCode::ArgParseError);
+    }
+
+    if options.repl_mode {
+        let unsupported = [
+            (options.ast_format != AstFormat::Unset,        "--emit-ast"),
+            (!options.header_file.is_empty(),               "--emit-header"),
+            (!options.rust_bindings_file.is_empty(),        "--emit-rust-bindings"),
+            (!options.entry_name.is_empty(),                "--entry-name"),
+            (options.body_type != BodyType::Unset,          "-k|--no-main|-C|--c-main"),
+            (options.codegen_type != CodeGenType::Unset,    "-b|--bitcode|-c|-s|--asm|-S|--llvmir|--shared|--archive"),
+            (options.eval_mode,                             "--eval"),
+            (options.input_format != InputFormat::Source,   "--input-format"),
+            (options.print_ast,                             "--ast"),
+            (options.ir_exit,                               "--ir"),
+            (options.lex_exit,                              "--lex"),
+            (!options.link_bitcode_files.is_empty(),        "--link-bitcode"),
+            (options.parse_exit,                            "--parse"),
+            (options.sem_exit,                              "--sem"),
+            (!kernel_args.is_empty(),                       "--"),
+            (matches!(*output, OutputType::File(_)),        "-o"),
+        ];
+        for (is_set, name) in unsupported {
+            if is_set {
+                eprintln!("Option '{}' is not supported by the 'repl' subcommand", name);
+                help(ExitCode::ArgParseError);
+            }
+        }
+        if let Some(input) = inputs.first() {
+            eprintln!("The 'repl' subcommand reads from Stdin and does not take an INPUT (found '{}')", input);
+            help(ExitCode::ArgParseError);
+        }
+    }
+
+    if options.eval_mode && options.fmt_mode {
+        eprintln!("Option '--eval' is not supported by the 'fmt' subcommand");
+        help(ExitCode::ArgParseError);
+    } else if !kernel_args.is_empty() && !options.eval_mode && !options.run_mode {
+        eprintln!("Arguments after '--' are only supported with '--eval' or '--run'");
+        help(ExitCode::ArgParseError);
+    }
+
+    if options.codegen_type == CodeGenType::Archive {
+        let unsupported = [
+            (options.ast_format != AstFormat::Unset,        "--emit-ast"),
+            (!options.entry_name.is_empty(),                "--entry-name"),
+            (options.eval_mode,                             "--eval"),
+            (options.fmt_mode,                              "fmt"),
+            (options.ir_exit,                               "--ir"),
+            (options.lex_exit,                              "--lex"),
+            (!options.link_bitcode_files.is_empty(),        "--link-bitcode"),
+            (options.parse_exit,                            "--parse"),
+            (options.run_mode,                              "--run"),
+            (options.sem_exit,                              "--sem"),
+        ];
+        for (is_set, name) in unsupported {
+            if is_set {
+                eprintln!("Option '{}' is not supported with '--archive'", name);
+                help(ExitCode::ArgParseError);
+            }
+        }
+    } else if inputs.len() > 1 {
+        let unsupported = [
+            (options.ast_format != AstFormat::Unset,        "--emit-ast"),
+            (!options.entry_name.is_empty(),                "--entry-name"),
+            (options.eval_mode,                             "--eval"),
+            (options.fmt_mode,                              "fmt"),
+            (options.run_mode,                              "--run"),
+        ];
+        for (is_set, name) in unsupported {
+            if is_set {
+                eprintln!("Option '{}' is not supported with more than one input", name);
+                help(ExitCode::ArgParseError);
+            }
+        }
+    }
+
+    if options.run_mode {
+        if options.fmt_mode {
+            eprintln!("Option '--run' is not supported by the 'fmt' subcommand");
+            help(ExitCode::ArgParseError);
+        } else if options.eval_mode {
+            eprintln!("Incompatible compiler flags: '--eval' and '--run'");
+            exit(ExitCode::ArgParseError);
+        } else if options.body_type == BodyType::MainGenC {
+            eprintln!("Incompatible compiler flags: '-C|--c-main' and '--run'");
+            exit(ExitCode::ArgParseError);
+        } else if options.codegen_type != CodeGenType::Unset {
+            eprintln!("Option '--run' does not produce output of type {}", options.codegen_type);
+            exit(ExitCode::ArgParseError);
+        } else if let OutputType::File(f) = *output {
+            eprintln!("Option '--run' does not produce an output file (found '-o {}')", f);
+            exit(ExitCode::ArgParseError);
+        }
+        // Only the kernel is compiled for the JIT
+        if options.body_type == BodyType::Unset {
+            set_body_type(options, BodyType::NoMain);
+        }
+    }
+
+    if options.input_format == InputFormat::AstJson {
+        if options.fmt_mode {
+            eprintln!("Option '--input-format=ast-json' is not supported by the 'fmt' subcommand");
+            help(ExitCode::ArgParseError);
+        } else if options.lex_exit {
+            eprintln!("Option '--lex' is not supported with '--input-format=ast-json'");
+            help(ExitCode::ArgParseError);
+        } else if options.asm_source {
+            eprintln!("Option '--asm-source' is not supported with '--input-format=ast-json'");
+            help(ExitCode::ArgParseError);
+        } else if options.debug_info {
+            eprintln!("Option '-g' is not supported with '--input-format=ast-json'");
+            help(ExitCode::ArgParseError);
+        }
+    }
+
+    if options.no_target && !options.target_triple.is_empty() {
+        eprintln!("Option '--notarget' is not supported with '--target'");
+        help(ExitCode::ArgParseError);
+    }
+
+    if options.no_codegen() {
+        let unsupported = [
+            (!options.header_file.is_empty(),               "--emit-header"),
+            (!options.rust_bindings_file.is_empty(),        "--emit-rust-bindings"),
+            (!options.link_bitcode_files.is_empty(),        "--link-bitcode"),
+        ];
+        for (is_set, name) in unsupported {
+            if is_set {
+                eprintln!("Option '{}' is not supported with '--emit-ast', '--eval', or 'fmt'", name);
+                help(ExitCode::ArgParseError);
+            }
+        }
+    }
+
+    if inputs.iter().filter(|input| **input == InputType::Stdin).count() > 1 {
+        eprintln!("Found more than one input from Stdin ('-')");
+        help(ExitCode::ArgParseError);
+    }
+
+    if options.asm_source && options.codegen_type != CodeGenType::Assembly {
+        eprintln!("Option '--asm-source' is only supported with '-s|--asm'");
+        help(ExitCode::ArgParseError);
+    }
+
+    if options.codegen_type == CodeGenType::SharedLib || options.codegen_type == CodeGenType::Archive {
+        if options.body_type == BodyType::MainGenC {
+            let flag = if options.codegen_type == CodeGenType::SharedLib { "--shared" } else { "--archive" };
+            eprintln!("Incompatible compiler flags: '-C|--c-main' and '{}'", flag);
+            exit(ExitCode::ArgParseError);
+        }
+        // Only the kernels are exported from the shared library or archive
+        if options.body_type == BodyType::Unset {
+            set_body_type(options, BodyType::NoMain);
+        }
+    }
+
     if options.body_type == BodyType::Unset {
         set_body_type(options, BodyType::MainGen);
     }
@@ -263,11 +580,23 @@ fn parse_args<'a>(
         set_codegen_type(options, CodeGenType::Executable);
     }
 
-    if *input == InputType::None {
+    // The reserved names depend on the generated code (e.g., the C main of '-C')
+    if !options.entry_name.is_empty() && !Semantics::check_entry_name(&options.entry_name, options) {
+        help(ExitCode::ArgParseError);
+    }
+
+    if !options.link_args.is_empty() && !is_linked {
+        eprintln!("Option '--link-arg' is only supported for executables and shared libraries");
+        help(ExitCode::ArgParseError);
+    }
+
+    if inputs.is_empty() && !options.repl_mode {
         eprintln!("No input file/name specified!");
         help(ExitCode::ArgParseError);
     } else if options.verbose {
-        eprintln!("Processing input '{}'", *input);
+        for input in inputs.iter() {
+            eprintln!("Processing input '{}'", input);
+        }
     }
 
     if options.verbose {
@@ -296,11 +625,16 @@ fn parse_arg_after<'a>(args: &'a [String], i: &mut usize) -> &'a str {
 
 fn parse_arg_complex<'a>(
     arg: &'a String,
-    input: &mut InputType<'a>,
+    inputs: &mut Vec<InputType<'a>>,
     output: &mut OutputType<'a>,
+    link_flags: &mut Vec<&'a String>,
+    options: &mut RunOptions,
 ) {
     let lead_char: char = arg.chars().next().unwrap();
-    if arg.len() > 1 && lead_char == '-' {
+    if arg.len() > 2 && (arg.starts_with("-l") || arg.starts_with("-L")) {
+        options.link_args.push(arg.to_string());
+        link_flags.push(arg);
+    } else if arg.len() > 1 && lead_char == '-' {
         match arg.find('=') {
             None    => {
                 eprintln!("Unrecognized argument '{}'", arg);
@@ -308,9 +642,21 @@ fn parse_arg_complex<'a>(
             }
             Some(j) => {
                 match &arg[0..j] {
-                    "-e"        => *input = InputType::Expr(&arg[j + 1..]),
-                    "--expr"    => *input = InputType::Expr(&arg[j + 1..]),
+                    "--cpu"     => options.target_cpu = arg[j + 1..].to_string(),
+                    "-e"        => inputs.push(InputType::Expr(&arg[j + 1..])),
+                    "--emit-ast" => options.ast_format = parse_ast_format(&arg[j + 1..]),
+                    "--emit-header" => options.header_file = arg[j + 1..].to_string(),
+                    "--emit-rust-bindings" => options.rust_bindings_file = arg[j + 1..].to_string(),
+                    "--entry-name" => options.entry_name = arg[j + 1..].to_string(),
+                    "--expr"    => inputs.push(InputType::Expr(&arg[j + 1..])),
+                    "--features" => options.target_features = parse_features(&arg[j + 1..]),
+                    "--input-format" => options.input_format = parse_input_format(&arg[j + 1..]),
+                    "--link-arg" => options.link_args.push(arg[j + 1..].to_string()),
+                    "--link-bitcode" => options.link_bitcode_files.push(arg[j + 1..].to_string()),
+                    "-march"    => options.target_cpu = arg[j + 1..].to_string(),
                     "-o"        => *output = OutputType::new(&arg[j + 1..]),
+                    "--passes"  => options.passes = parse_passes(&arg[j + 1..]),
+                    "--target"  => options.target_triple = arg[j + 1..].to_string(),
                     _           => {
                         eprintln!("Unrecognized argument '{}'", arg);
                         help(ExitCode::ArgParseError);
@@ -318,14 +664,124 @@ fn parse_arg_complex<'a>(
                 }
             }
         }
-    } else if *input != InputType::None {
-        eprintln!("Found more than one input ('{}' and '{}')", *input, arg);
-        help(ExitCode::ArgParseError);
     } else if arg.len() == 1 && lead_char == '-' {
-        *input = InputType::Stdin;
+        inputs.push(InputType::Stdin);
     } else {
-        *input = InputType::File(arg.as_str());
+        inputs.push(InputType::File(arg.as_str()));
+    }
+}
+
+fn parse_kernel_args(args: &[String], kernel_args: &mut Vec<i64>) -> () {
+    for arg in args {
+        match arg.parse::<i64>() {
+            Ok(n)   => kernel_args.push(n),
+            Err(_)  => {
+                eprintln!("Expected integer argument after '--' (found '{}')", arg);
+                help(ExitCode::ArgParseError);
+            },
+        }
+    }
+}
+
+/// Checks that each feature in the comma separated list is enabled ('+') or disabled ('-').
+fn parse_features(features: &str) -> String {
+    for feature in features.split(',') {
+        if feature.len() < 2 || !(feature.starts_with('+') || feature.starts_with('-')) {
+            eprintln!("Invalid feature '{}' in '{}' (expected '+<feature>' or '-<feature>')", feature, features);
+            help(ExitCode::ArgParseError);
+        }
+    }
+    features.to_string()
+}
+
+fn parse_passes(passes: &str) -> String {
+    if passes.trim().is_empty() {
+        eprintln!("Expected a non-empty pass pipeline for '--passes'");
+        help(ExitCode::ArgParseError);
     }
+    passes.to_string()
+}
+
+fn parse_ast_format(name: &str) -> AstFormat {
+    match name {
+        "json"  => AstFormat::Json,
+        "sexpr" => AstFormat::Sexpr,
+        "dot"   => AstFormat::Dot,
+        _       => {
+            eprintln!("Unrecognized AST format '{}' (expected json|sexpr|dot)", name);
+            help(ExitCode::ArgParseError);
+        },
+    }
+}
+
+fn parse_input_format(name: &str) -> InputFormat {
+    match name {
+        "ast-json"  => InputFormat::AstJson,
+        "source"    => InputFormat::Source,
+        _           => {
+            eprintln!("Unrecognized input format '{}' (expected source|ast-json)", name);
+            help(ExitCode::ArgParseError);
+        },
+    }
+}
+
+fn write_text_output(output: &OutputType, text: &str) -> bool {
+    match *output {
+        OutputType::Stdout  => print!("{}", text),
+        OutputType::File(f) => {
+            let mut file = match File::create(f) {
+                Ok(file)    => file,
+                Err(msg)    => {
+                    eprintln!("Failed to open output file '{}': {}", f, msg);
+                    return false;
+                },
+            };
+            if let Err(msg) = file.write_all(text.as_bytes()) {
+                eprintln!("Failed to write to output file '{}': {}", f, msg);
+                return false;
+            }
+        },
+    };
+    true
+}
+
+fn format_source(
+    ast: &dyn Ast,
+    tokens: &[Token],
+    comments: &[Token],
+    source: &str,
+    input: &InputType,
+    output: &OutputType,
+    options: &RunOptions,
+) -> ! {
+    let formatted: String = match PrettyPrinter::format(ast, tokens, comments, source) {
+        Some(formatted) => formatted,
+        None            => exit(ExitCode::FormatError),
+    };
+    if options.fmt_check {
+        // The final newline is not part of the program (e.g., it is missing for '-e' inputs)
+        let is_formatted = formatted.strip_suffix('\n').unwrap_or(&formatted) == source.strip_suffix('\n').unwrap_or(source);
+        if !is_formatted {
+            eprintln!("Input '{}' is not formatted", input);
+            exit(ExitCode::FormatError);
+        } else if options.verbose {
+            eprintln!("Input '{}' is formatted", input);
+        }
+    } else if !write_text_output(output, &formatted) {
+        exit(ExitCode::WriteError);
+    }
+    exit(ExitCode::Ok);
+}
+
+fn emit_ast(ast: &dyn Ast, output: &OutputType, options: &RunOptions) -> ! {
+    let text: String = match AstSerializer::serialize(ast, options.ast_format) {
+        Some(text)  => text,
+        None        => exit(ExitCode::WriteError),
+    };
+    if !write_text_output(output, &text) {
+        exit(ExitCode::WriteError);
+    }
+    exit(ExitCode::Ok);
 }
 
 fn print_pkg_info(should_exit: bool) {
@@ -333,73 +789,321 @@ fn print_pkg_info(should_exit: bool) {
     if should_exit { exit(ExitCode::Ok); }
 }
 
-fn main() -> ! {
-    let args: Vec<String> = env::args().collect();
-    let mut input: InputType = InputType::None;
-    let mut output: OutputType = OutputType::Stdout;
-    let mut options: RunOptions = RunOptions::new();
-
-    parse_args(&args, &mut input, &mut output, &mut options);
+/// The kernel generated for an INPUT, with what is needed to write the output.
+struct Kernel {
+    f_sig: FunctionSignature,
+    name: String,
+    source: String,
+    vars: Vars,
+}
 
-    let mut tokens: Vec<Token> = Vec::new();
-    match input {
-        InputType::None     => {
-            eprintln!("Unexpected input");
-            exit(ExitCode::ArgParseError);
-        }
-        InputType::Stdin    => {
-            let mut lex = Lexer::new(stdin(), &options);
-            Lexer::lex_input(&mut tokens, &mut lex, &options);
-        }
-        InputType::Expr(e)  => {
-            let mut lex = Lexer::new(Cursor::new(e.to_string()), &options);
-            Lexer::lex_input(&mut tokens, &mut lex, &options);
-        }
+/// Reads, checks, and generates the kernel for `input` into a module named `module_name` (in the
+/// context of `context` if given).
+/// The kernel is named by '--entry-name', by the 'name:' declaration of `input`, or else by
+/// `default_name`.
+/// Returns None if the input has been checked up to the last stage requested by '--lex', '--parse',
+/// or '--sem' (i.e., no kernel is generated).
+#[allow(clippy::too_many_arguments)]
+fn gen_kernel<'a>(
+    input: &InputType,
+    output: &OutputType,
+    module_name: &'a String,
+    context: Option<&ModuleBundle>,
+    default_name: &str,
+    kernel_args: &[i64],
+    producer: &str,
+    options: &RunOptions,
+) -> Option<(ModuleBundle<'a>, Kernel)> {
+    let source: String = match *input {
+        InputType::Stdin    => read_source(stdin()),
+        InputType::Expr(e)  => e.to_string(),
         InputType::File(f)  => {
             let file: File = File::open(f).expect("Failed to open input file");
-            let mut lex = Lexer::new(file, &options);
-            Lexer::lex_input(&mut tokens, &mut lex, &options);
+            read_source(file)
         }
-    }
+    };
 
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut comments: Vec<Token> = Vec::new();
     let mut expr_tmp: Expr = Default::default();
     let mut ast: Box<&mut dyn Ast> = Box::new(&mut expr_tmp);
-    let mut parser: Parser = Parser::new(&tokens, &options);
-    Parser::parse_input(&mut ast, &mut parser, &options);
+    let mut parser: Parser;
+    match options.input_format {
+        InputFormat::AstJson    => AstReader::read_input(&mut ast, &source, options),
+        InputFormat::Source     => {
+            let mut lex = Lexer::new(&source, options);
+            Lexer::lex_input(&mut tokens, &mut comments, &mut lex, options).unwrap_or_else(|code| exit(code));
+            if options.lex_exit { return None; }
 
-    let sem_check: bool = Semantics::check_all(*ast, &options);
-    assert!(sem_check);
+            parser = Parser::new(&tokens, &source, options);
+            Parser::parse_input(&mut ast, &mut parser, options).unwrap_or_else(|code| exit(code));
+        },
+    };
+    if options.parse_exit { return None; }
 
-    let module_name_irgen = String::from("calcc");
-    let mut module_irgen = ModuleBundle::new(&module_name_irgen, options.verbose);
-    let irgen_status: bool = IRGen::gen(*ast, &mut module_irgen);
-    assert!(irgen_status);
-    let irgen_verify: bool = module_irgen.verify_module();
+    if options.fmt_mode {
+        format_source(*ast, &tokens, &comments, &source, input, output, options);
+    }
+
+    Semantics::check_all(*ast, options).unwrap_or_else(|code| exit(code));
+    let ast_folded: &mut dyn Ast = ConstFold::fold_all(*ast, options).unwrap_or_else(|code| exit(code));
+    if options.sem_exit { return None; }
+
+    // The AST is emitted as parsed (i.e., before constant folding)
+    if options.ast_format != AstFormat::Unset {
+        emit_ast(*ast, output, options);
+    }
+    *ast = ast_folded;
+
+    if options.eval_mode {
+        Interpreter::eval(*ast, kernel_args, options);
+    }
+
+    let name: String = match (options.entry_name.as_str(), get_name_decl(*ast)) {
+        ("", Some(name))    => name,
+        ("", None)          => default_name.to_string(),
+        (name, _)           => name.to_string(),
+    };
+    let mut bundle = match context {
+        Some(other) => ModuleBundle::new_in_context_of(module_name, other, options.verbose),
+        None        => ModuleBundle::new(module_name, options.verbose),
+    };
+    // The line table of the debug info is also used to annotate the assembly with '--asm-source'
+    let debug_info: Option<DebugInfo> = if options.debug_info || options.asm_source {
+        let file_name = match *input {
+            InputType::File(f)  => f,
+            _                   => "-",
+        };
+        Some(DebugInfo::new(&bundle, &source, file_name, producer, options))
+    } else {
+        None
+    };
+    let irgen_status: bool = IRGen::gen(*ast, &mut bundle, &name, options.opt_level, debug_info);
+    if !irgen_status {
+        exit(ExitCode::IRGenError);
+    }
+    let irgen_verify: bool = bundle.verify_module();
     if !irgen_verify {
         eprintln!("IRGen module failed to verify");
         exit(ExitCode::VerifyError);
     }
 
+    let vars: Vars = get_decl_vars(*ast);
+    let f_sig: FunctionSignature = bundle.f_sig.clone().unwrap();
+    Some((bundle, Kernel{f_sig, name, source, vars}))
+}
+
+/// Checks that the name of `kernel` (generated for `inputs[kernels.len()]`) can be linked with
+/// the other `kernels`.
+fn check_kernel_name(kernel: &Kernel, kernels: &[Kernel], inputs: &[InputType], options: &RunOptions) -> () {
+    let input = &inputs[kernels.len()];
+    if !Semantics::check_entry_name(&kernel.name, options) {
+        eprintln!("Please add a name declaration (i.e., 'name: <kernel>:') to input '{}'", input);
+        exit(ExitCode::SemanticError);
+    } else if let Some(i) = kernels.iter().position(|other| other.name == kernel.name) {
+        eprintln!("Found duplicate kernel name '{}' (inputs '{}' and '{}')", kernel.name, inputs[i], input);
+        exit(ExitCode::SemanticError);
+    }
+}
+
+/// Generates the kernels of `inputs` into a single module named `module_name`.
+/// A single kernel is named `default_name` by default, and several kernels are named as in an
+/// archive (see `get_kernel_name_from_input`).
+fn gen_kernels<'a>(
+    inputs: &[InputType],
+    output: &OutputType,
+    module_name: &'a String,
+    default_name: &str,
+    kernel_args: &[i64],
+    producer: &str,
+    options: &RunOptions,
+) -> (ModuleBundle<'a>, Vec<Kernel>) {
+    let mut module: Option<ModuleBundle<'a>> = None;
+    let mut kernels: Vec<Kernel> = Vec::new();
+    for input in inputs {
+        let kernel_name = if inputs.len() == 1 {
+            default_name.to_string()
+        } else {
+            get_kernel_name_from_input(input, default_name)
+        };
+        // The kernels share the context of the first module (through its `Rc<ContextOwner>`), so that
+        // they can be linked
+        let Some((mut bundle, kernel)) = gen_kernel(
+            input, output, module_name, module.as_ref(), &kernel_name, kernel_args, producer, options
+        ) else {
+            continue;
+        };
+        check_kernel_name(&kernel, &kernels, inputs, options);
+        match module {
+            None                => module = Some(bundle),
+            Some(ref mut module) => if !module.link_into(&mut bundle) {
+                eprintln!("Failed to link kernel '{}' into module", kernel.name);
+                exit(ExitCode::LinkError);
+            },
+        };
+        kernels.push(kernel);
+    }
+    // Every input is checked before exiting for '--lex', '--parse', or '--sem'
+    if options.lex_exit || options.parse_exit || options.sem_exit {
+        exit(ExitCode::Ok);
+    }
+    (module.unwrap(), kernels)
+}
+
+/// Returns the default name of the kernel for `input` in an archive or a module with several
+/// kernels (i.e., the file stem of `input` as an identifier, or `default_name` if `input` is not
+/// a file).
+fn get_kernel_name_from_input(input: &InputType, default_name: &str) -> String {
+    match *input {
+        InputType::File(f)  => get_ident_from_stem(f),
+        _                   => default_name.to_string(),
+    }
+}
+
+/// Returns the file stem of `f` with each character which is not valid in an identifier
+/// replaced by '_' (e.g., 'my-kernel.calc' is named 'my_kernel').
+fn get_ident_from_stem(f: &str) -> String {
+    let stem: &str = Path::new(f).file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let mut ident: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if !ident.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+/// Writes the C header and Rust bindings declaring the kernels (if requested).
+fn write_declarations(kernels: &[(&FunctionSignature, &Vars)], guard_name: &str, producer: &str, options: &RunOptions) {
+    if !options.header_file.is_empty() {
+        let header = HeaderGen::gen(kernels, guard_name, producer);
+        if !write_text_output(&OutputType::new(&options.header_file), &header) {
+            exit(ExitCode::WriteError);
+        }
+    }
+    if !options.rust_bindings_file.is_empty() {
+        let bindings = RustBindingsGen::gen(kernels, producer);
+        if !write_text_output(&OutputType::new(&options.rust_bindings_file), &bindings) {
+            exit(ExitCode::WriteError);
+        }
+    }
+}
+
+/// Compiles each of `inputs` to an object file defining its kernel and writes the objects to the
+/// static archive `output` (with '--archive').
+fn write_archive(inputs: &[InputType], output: &OutputType, producer: &str, options: &RunOptions) -> ! {
+    let f: &str = match *output {
+        OutputType::File(f) => f,
+        OutputType::Stdout  => {
+            eprintln!("Output to Stdout not supported for archives");
+            exit(ExitCode::ArgParseError);
+        },
+    };
+
+    let mut target = Target::new(&options.target_triple);
+    if options.verbose {
+        eprintln!("Detected target triple '{}'", target.get_string());
+    }
+    let mut machine = TargetMachine::new(
+        &mut target,
+        options.opt_level,
+        &options.target_cpu,
+        &options.target_features,
+        options.verbose
+    );
+    let mut pass_builder = PassBuilder::new(options);
+
+    let module_name = String::from("calcc");
+    let default_name = format!("{}_main", module_name);
+    let mut kernels: Vec<Kernel> = Vec::new();
+    let mut members: Vec<ArchiveMember> = Vec::new();
+    for input in inputs {
+        // Each kernel is compiled in its own module, so that the kernels can be linked separately
+        let kernel_name = get_kernel_name_from_input(input, &default_name);
+        let (mut bundle, kernel) = gen_kernel(input, output, &module_name, None, &kernel_name, &[], producer, options)
+            .expect("Unexpected early exit for archive");
+        check_kernel_name(&kernel, &kernels, inputs, options);
+
+        match *input {
+            InputType::File(f)  => bundle.set_sourcefile_name(f),
+            _                   => bundle.set_sourcefile_name("-"),
+        };
+        let opt_result = pass_builder.run(&mut bundle, &mut machine, Passes::from_options(options), options.no_target);
+        if !opt_result {
+            exit(ExitCode::TargetError);
+        }
+
+        let object = bundle.emit_object(&machine);
+        members.push(ArchiveMember::new(format!("{}.o", kernel.name), object, vec![kernel.name.clone()]));
+        kernels.push(kernel);
+    }
+
+    let declarations: Vec<(&FunctionSignature, &Vars)> = kernels.iter()
+        .map(|kernel| (&kernel.f_sig, &kernel.vars))
+        .collect();
+    write_declarations(&declarations, &get_ident_from_stem(f), producer, options);
+
+    if !ArchiveWriter::write(f, &members, options.get_target_os()) {
+        eprintln!("Failed to write archive to output");
+        exit(ExitCode::WriteError);
+    }
+
+    exit(ExitCode::Ok);
+}
+
+fn main() -> ! {
+    let args: Vec<String> = env::args().collect();
+    let mut inputs: Vec<InputType> = Vec::new();
+    let mut output: OutputType = OutputType::Stdout;
+    let mut kernel_args: Vec<i64> = Vec::new();
+    let mut options: RunOptions = RunOptions::new();
+
+    parse_args(&args, &mut inputs, &mut output, &mut kernel_args, &mut options);
+
+    if options.repl_mode {
+        Repl::run(&options);
+    }
+
+    let producer = format!("{} {}", PACKAGE, VERSION);
+    if options.codegen_type == CodeGenType::Archive {
+        write_archive(&inputs, &output, &producer, &options);
+    }
+
+    let input: InputType = inputs[0];
+    let module_name_irgen = String::from("calcc");
+    let default_name = format!("{}_main", module_name_irgen);
+    let (mut module_irgen, kernels) = gen_kernels(
+        &inputs, &output, &module_name_irgen, &default_name, &kernel_args, &producer, &options
+    );
+    for f in options.link_bitcode_files.iter() {
+        if !module_irgen.link_bitcode_file(f) {
+            exit(ExitCode::LinkError);
+        }
+    }
+
+    let f_sigs: Vec<FunctionSignature> = kernels.iter().map(|kernel| kernel.f_sig.clone()).collect();
+    let declarations: Vec<(&FunctionSignature, &Vars)> = kernels.iter()
+        .map(|kernel| (&kernel.f_sig, &kernel.vars))
+        .collect();
+    let guard_name: String = match (kernels.as_slice(), output) {
+        ([kernel], _)           => kernel.name.clone(),
+        (_, OutputType::File(f)) => get_ident_from_stem(f),
+        (_, OutputType::Stdout) => module_name_irgen.clone(),
+    };
+    write_declarations(&declarations, &guard_name, &producer, &options);
+
     let module_name_main = String::from("main");
     let mut module_main = match options.body_type {
         BodyType::NoMain    => module_irgen,
         BodyType::MainGen   => {
-            let mut module_main = ModuleBundle::new(&module_name_main, options.verbose);
+            // The main module shares the context of the kernels, so the kernel signatures can be
+            // used directly and the linked module verifies.
+            let mut module_main = ModuleBundle::new_in_context_of(&module_name_main, &module_irgen, options.verbose);
 
-            // NOTE: Fixing the verifier errors by reconstructing the callee signature using
-            // the correct main module context causes the link step to omit the body of the callee
-            // (emitting only a declaration).
-            //let f_sig = module_main.get_f_sig_from_context(&module_irgen.f_sig.clone().unwrap());
-            let f_sig = &module_irgen.f_sig.clone().unwrap();
-
-            let maingen_status: bool = MainGen::gen(&mut module_main, f_sig);
+            let maingen_status: bool = MainGen::gen(&mut module_main, &f_sigs);
             if !maingen_status {
                 eprintln!("Failed to generate MainGen module");
                 exit(ExitCode::MainGenError);
             }
 
-            // NOTE: Linking modules causes verifier errors for unmatched contexts of LLVMTypeRef.
-            // Skip verification.
             let link_status: bool = module_main.link_into(&mut module_irgen);
             if !link_status {
                 eprintln!("IRGen and MainGen modules failed to link");
@@ -411,7 +1115,6 @@ fn main() -> ! {
         BodyType::MainGenC  => {
             // Generating the main.c object need to happen until after the target/optimization step,
             // but to appease the borrow checker, we do it here.
-            let f_sig = &module_irgen.f_sig.clone().unwrap();
             let stem = format!("{}pid{}",
                 match input {
                     InputType::File(f)  => Path::new(f).file_stem().unwrap().to_str().unwrap(),
@@ -419,7 +1122,7 @@ fn main() -> ! {
                 },
                 process::id()
             );
-            let main_obj_path = match MainGenC::gen(f_sig, &stem, options.verbose) {
+            let main_obj_path = match MainGenC::gen(&f_sigs, &stem, &options.target_triple, options.verbose) {
                 Some(path)  => path,
                 None        => {
                     eprintln!("Failed to generate MainGenC object file");
@@ -436,33 +1139,54 @@ fn main() -> ! {
         },
     };
 
+    for object in options.link_objects.iter() {
+        module_main.push_object(object.clone());
+    }
+
     match input {
         InputType::File(f)  => module_main.set_sourcefile_name(f),
         _                   => module_main.set_sourcefile_name("-"),
     };
 
+    if !module_main.verify_module() {
+        eprintln!("Linked module failed to verify");
+        exit(ExitCode::VerifyError);
+    }
+
     if options.ir_exit {
         eprintln!("{}", module_main);
         exit(ExitCode::Ok);
     }
 
-    let mut target = Target::new();
+    let mut target = Target::new(&options.target_triple);
     if options.verbose {
         eprintln!("Detected target triple '{}'", target.get_string());
     }
-    let mut machine = TargetMachine::new(&mut target, options.opt_level);
-    let mut pass_builder = PassBuilder::new();
-    let opt_result = pass_builder.run(
-        &mut module_main,
-        &mut machine,
-        Passes::Default(options.opt_level),
-        options.no_target
+    if options.run_mode && !target.is_host() {
+        eprintln!("Option '--run' is only supported for the host target (found '{}')", target.get_string());
+        exit(ExitCode::JitError);
+    }
+    let mut machine = TargetMachine::new(
+        &mut target,
+        options.opt_level,
+        &options.target_cpu,
+        &options.target_features,
+        options.verbose
     );
+    let mut pass_builder = PassBuilder::new(&options);
+    let opt_result = pass_builder.run(&mut module_main, &mut machine, Passes::from_options(&options), options.no_target);
     if !opt_result {
         exit(ExitCode::TargetError);
     }
 
-    let write_result = module_main.write_module(&options, &output);
+    if options.run_mode {
+        Jit::run(&mut module_main, &kernel_args, &options);
+    }
+
+    let annotations: Vec<(&str, &str)> = kernels.iter()
+        .map(|kernel| (kernel.source.as_str(), kernel.name.as_str()))
+        .collect();
+    let write_result = module_main.write_module(&machine, &options, &output, &annotations);
     if !write_result {
         eprintln!("Failed to write module to output");
         exit(ExitCode::WriteError);
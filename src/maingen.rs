@@ -1,11 +1,13 @@
 // Copyright 2024, Giordano Salvador
 // SPDX-License-Identifier: BSD-3-Clause
 
-/// Manually generate LLVM IR for a main function, using the C standard library, which calls the calcc
-/// program function generated for the input program.
-/// This approach is used to avoid interfacing with a stub program written in C, directly calling
-/// the dependent library functions.
-/// Omit this code generation by passing '--no-main' to calcc.
+//! Manually generate LLVM IR for a main function, using the C standard library, which calls the calcc
+//! program function generated for the input program.
+//! This approach is used to avoid interfacing with a stub program written in C, directly calling
+//! the dependent library functions.
+//! If several kernels are compiled into the module, the main function dispatches to the kernel
+//! named by its first argument.
+//! Omit this code generation by passing '--no-main' to calcc.
 
 extern crate llvm_sys as llvm;
 
@@ -32,6 +34,7 @@ use llvm::LLVMIntPredicate;
 
 use std::ffi::c_char;
 use std::ffi::c_uint;
+use std::slice;
 
 use crate::module;
 
@@ -39,10 +42,10 @@ use module::FunctionSignature;
 use module::ModuleBundle;
 
 static NAME_ATOLL       : &str = "atoll";
-static NAME_CALCC_MAIN  : &str = "calcc_main";
 static NAME_FPRINTF     : &str = "fprintf";
 static NAME_PRINTF      : &str = "printf";
 static NAME_STDERR      : &str = "stderr";
+static NAME_STRCMP      : &str = "strcmp";
 
 static NAME_ARG_ERR     : &str = ".str.argerr";
 static STRING_ARG_ERR   : &str = "Invalid number of args to main. Expected %d args\n\0";
@@ -50,6 +53,9 @@ static NAME_RESULT_STR  : &str = ".str.result";
 static STRING_RESULT_STR: &str = "calcc_main result: %lld\n\0";
 static NAME_USAGE       : &str = ".str.usage";
 static STRING_USAGE     : &str = "<exe> [<arg0>, <arg1>, ...]\n\0";
+static STRING_USAGE_DISPATCH: &str = "<exe> <kernel> [<arg0>, <arg1>, ...]\n\0";
+static NAME_KERNEL_ERR  : &str = ".str.kernelerr";
+static PREFIX_KERNEL_STR: &str = ".str.kernel.";
 
 static NAME_RETVAL      : &str = "vRet";
 static NAME_ARGC        : &str = "vArgc";
@@ -64,26 +70,75 @@ impl <'a, 'b> MainGen<'a, 'b> {
         MainGen{bundle}
     }
 
-    pub fn gen(bundle: &'a mut ModuleBundle<'b>, callee_sig: &'a FunctionSignature) -> bool {
+    /// Generates a main function calling the kernel with signature in `callee_sigs`, or dispatching
+    /// to one of the kernels by name if there are several.
+    pub fn gen(bundle: &'a mut ModuleBundle<'b>, callee_sigs: &'a [FunctionSignature]) -> bool {
         let mut maingen: Self = Self::new(bundle);
         let _printf = maingen.declare_atoll();
         let _fprintf = maingen.declare_fprintf();
         let _printf = maingen.declare_printf();
         let _stderr = maingen.declare_stderr();
-        let _calcc_main = maingen.declare_calcc_main(callee_sig);
-        let bb_entry = maingen.make_entry_block();
-        let bb_err = maingen.make_err_block();
-        let bb_body = maingen.make_body_block();
-        let bb_ret = maingen.make_ret_block();
-        maingen.declare_global_strings(); // NOTE: Needs to be called after first use of builder
-        let callee_values = maingen.gen_entry_stack(bb_entry, callee_sig);
-        maingen.gen_entry_branch(bb_entry, bb_err, bb_body, callee_sig.params.len());
-        maingen.gen_err_block(bb_err, bb_ret, callee_sig.params.len());
-        maingen.gen_body(bb_body, bb_ret, callee_sig, &callee_values);
-        maingen.gen_ret(bb_ret);
+        match callee_sigs {
+            [callee_sig]    => maingen.gen_main(callee_sig),
+            _               => maingen.gen_main_dispatch(callee_sigs),
+        };
         true
     }
 
+    fn gen_main(&mut self, callee_sig: &'a FunctionSignature) -> () {
+        let _calcc_main = self.declare_calcc_main(callee_sig);
+        let bb_entry = self.make_entry_block();
+        let bb_err = self.make_block("print_err");
+        let bb_body = self.make_block("body");
+        let bb_ret = self.make_block("ret_label");
+        self.declare_global_strings(STRING_USAGE); // NOTE: Needs to be called after first use of builder
+        let callee_values = self.gen_entry_stack(bb_entry, slice::from_ref(callee_sig)).remove(0);
+        let n = callee_sig.params.len();
+        self.gen_entry_branch(bb_entry, bb_err, bb_body, LLVMIntPredicate::LLVMIntNE, n + 1);
+        let value_num_args = self.bundle.get_constint(self.bundle.t_i32, n as i64);
+        self.gen_err_block(bb_err, bb_ret, NAME_ARG_ERR, &[value_num_args]);
+        self.gen_body(bb_body, bb_ret, callee_sig, &callee_values, 1);
+        self.gen_ret(bb_ret);
+    }
+
+    /// Generates a main function which compares its first argument to the name of each kernel, and
+    /// calls the matching kernel with the remaining arguments.
+    fn gen_main_dispatch(&mut self, callee_sigs: &'a [FunctionSignature]) -> () {
+        let _strcmp = self.declare_strcmp();
+        for callee_sig in callee_sigs {
+            let _calcc_main = self.declare_calcc_main(callee_sig);
+        }
+        let bb_entry = self.make_entry_block();
+        let bb_kernel_err = self.make_block("print_kernel_err");
+        let mut bbs_kernel: Vec<[LLVMBasicBlockRef; 4]> = Vec::new();
+        for callee_sig in callee_sigs {
+            bbs_kernel.push([
+                self.make_block(&format!("dispatch_{}", callee_sig.name)),
+                self.make_block(&format!("check_{}", callee_sig.name)),
+                self.make_block(&format!("print_err_{}", callee_sig.name)),
+                self.make_block(&format!("body_{}", callee_sig.name)),
+            ]);
+        }
+        let bb_ret = self.make_block("ret_label");
+        self.declare_global_strings(STRING_USAGE_DISPATCH); // NOTE: Needs to be called after first use of builder
+        self.declare_kernel_strings(callee_sigs);
+        let callee_values = self.gen_entry_stack(bb_entry, callee_sigs);
+        // The kernel name and its arguments follow the name of the executable
+        self.gen_entry_branch(bb_entry, bb_kernel_err, bbs_kernel[0][0], LLVMIntPredicate::LLVMIntSLT, 2);
+        self.gen_err_block(bb_kernel_err, bb_ret, NAME_KERNEL_ERR, &[]);
+        for (i, callee_sig) in callee_sigs.iter().enumerate() {
+            let [bb_dispatch, bb_check, bb_err, bb_body] = bbs_kernel[i];
+            let bb_next = bbs_kernel.get(i + 1).map(|bbs| bbs[0]).unwrap_or(bb_kernel_err);
+            let n = callee_sig.params.len();
+            self.gen_dispatch_branch(bb_dispatch, bb_check, bb_next, callee_sig);
+            self.gen_argc_branch(bb_check, bb_err, bb_body, LLVMIntPredicate::LLVMIntNE, n + 2);
+            let value_num_args = self.bundle.get_constint(self.bundle.t_i32, n as i64);
+            self.gen_err_block(bb_err, bb_ret, NAME_ARG_ERR, &[value_num_args]);
+            self.gen_body(bb_body, bb_ret, callee_sig, &callee_values[i], 2);
+        }
+        self.gen_ret(bb_ret);
+    }
+
     fn make_entry_block(&mut self) -> LLVMBasicBlockRef {
         let mut param_types: Vec<LLVMTypeRef> = vec![self.bundle.t_i32, self.bundle.t_opaque];
         let f_name = ModuleBundle::value_name(self.bundle.name.as_str());
@@ -97,7 +152,7 @@ impl <'a, 'b> MainGen<'a, 'b> {
             );
             let f = LLVMAddFunction(self.bundle.module, f_name.as_ptr() as *const c_char, t_f);
             self.bundle.f = Some(f);
-            self.bundle.f_sig = Some(FunctionSignature::new(t_ret, param_types));
+            self.bundle.f_sig = Some(FunctionSignature::new(self.bundle.name, t_ret, param_types));
             let bb = LLVMAppendBasicBlockInContext(
                 self.bundle.context,
                 f,
@@ -108,12 +163,14 @@ impl <'a, 'b> MainGen<'a, 'b> {
         }
     }
 
+    /// Stores the arguments of main and branches to `bb_err` if `argc <predicate> argc_expected`.
     fn gen_entry_branch(
         &mut self,
         bb_entry: LLVMBasicBlockRef,
         bb_err: LLVMBasicBlockRef,
         bb_body: LLVMBasicBlockRef,
-        callee_arg_len: usize,
+        predicate: LLVMIntPredicate,
+        argc_expected: usize,
     ) -> () {
         unsafe { LLVMPositionBuilderAtEnd(self.bundle.builder, bb_entry); };
         let name_ret = ModuleBundle::value_name(NAME_RETVAL);
@@ -123,9 +180,6 @@ impl <'a, 'b> MainGen<'a, 'b> {
         let value_argc = self.bundle.get_value(&name_argc);
         let value_argv = self.bundle.get_value(&name_argv);
         let init_value_zero = self.bundle.get_constint(self.bundle.t_i32, 0);
-        let cmp_value_args = self.bundle.get_constint(self.bundle.t_i32, (callee_arg_len + 1) as i64);
-        let name_argc_tmp = self.bundle.scope.next_value_name();
-        let name_icmp = self.bundle.scope.next_value_name();
         let f = self.bundle.f.unwrap();
         unsafe {
             let init_value_argc = LLVMGetParam(f, 0 as c_uint);
@@ -133,6 +187,26 @@ impl <'a, 'b> MainGen<'a, 'b> {
             let _ = LLVMBuildStore(self.bundle.builder, init_value_zero, value_ret);
             let _ = LLVMBuildStore(self.bundle.builder, init_value_argc, value_argc);
             let _ = LLVMBuildStore(self.bundle.builder, init_value_argv, value_argv);
+        };
+        self.gen_argc_branch(bb_entry, bb_err, bb_body, predicate, argc_expected);
+    }
+
+    /// Branches from `bb_from` to `bb_err` if `argc <predicate> argc_expected`, else to `bb_body`.
+    fn gen_argc_branch(
+        &mut self,
+        bb_from: LLVMBasicBlockRef,
+        bb_err: LLVMBasicBlockRef,
+        bb_body: LLVMBasicBlockRef,
+        predicate: LLVMIntPredicate,
+        argc_expected: usize,
+    ) -> () {
+        unsafe { LLVMPositionBuilderAtEnd(self.bundle.builder, bb_from); };
+        let name_argc = ModuleBundle::value_name(NAME_ARGC);
+        let value_argc = self.bundle.get_value(&name_argc);
+        let cmp_value_args = self.bundle.get_constint(self.bundle.t_i32, argc_expected as i64);
+        let name_argc_tmp = self.bundle.scope.next_value_name();
+        let name_icmp = self.bundle.scope.next_value_name();
+        unsafe {
             let value_argc_tmp = LLVMBuildLoad2(
                 self.bundle.builder,
                 self.bundle.t_i32,
@@ -141,7 +215,7 @@ impl <'a, 'b> MainGen<'a, 'b> {
             );
             let value_icmp = LLVMBuildICmp(
                 self.bundle.builder,
-                LLVMIntPredicate::LLVMIntNE,
+                predicate,
                 value_argc_tmp,
                 cmp_value_args,
                 name_icmp.as_ptr() as *const c_char
@@ -150,26 +224,60 @@ impl <'a, 'b> MainGen<'a, 'b> {
         };
     }
 
-    fn make_err_block(&mut self) -> LLVMBasicBlockRef {
-        let f = self.bundle.f.unwrap();
+    /// Branches from `bb_dispatch` to `bb_match` if the first argument of main is the name of the
+    /// kernel with signature `callee_sig`, else to `bb_next`.
+    fn gen_dispatch_branch(
+        &mut self,
+        bb_dispatch: LLVMBasicBlockRef,
+        bb_match: LLVMBasicBlockRef,
+        bb_next: LLVMBasicBlockRef,
+        callee_sig: &'a FunctionSignature,
+    ) -> () {
+        unsafe { LLVMPositionBuilderAtEnd(self.bundle.builder, bb_dispatch); };
+        let name_strcmp = ModuleBundle::value_name(NAME_STRCMP);
+        let name_kernel_str = ModuleBundle::value_name(&format!("{}{}", PREFIX_KERNEL_STR, callee_sig.name));
+        let value_strcmp = self.bundle.get_value(&name_strcmp);
+        let value_kernel_str = self.bundle.get_value(&name_kernel_str);
+        let value_zero = self.bundle.get_constint(self.bundle.t_i32, 0);
+        let value_arg = self.gen_load_argv(1);
+        let name_call_tmp = self.bundle.scope.next_value_name();
+        let name_icmp = self.bundle.scope.next_value_name();
+        let mut params_strcmp: Vec<LLVMValueRef> = vec![value_arg, value_kernel_str];
+        let mut param_types: Vec<LLVMTypeRef> = vec![self.bundle.t_opaque, self.bundle.t_opaque];
         unsafe {
-            let bb = LLVMAppendBasicBlockInContext(
-                self.bundle.context,
-                f,
-                ModuleBundle::value_name("print_err").as_ptr() as *const c_char,
+            let t_f = LLVMFunctionType(
+                self.bundle.t_i32,
+                param_types.as_mut_ptr(),
+                param_types.len() as u32,
+                false as LLVMBool
             );
-            LLVMPositionBuilderAtEnd(self.bundle.builder, bb);
-            bb
-        }
+            let value_call_tmp = LLVMBuildCall2(
+                self.bundle.builder,
+                t_f,
+                value_strcmp,
+                params_strcmp.as_mut_ptr(),
+                params_strcmp.len() as c_uint,
+                name_call_tmp.as_ptr() as *const c_char
+            );
+            let value_icmp = LLVMBuildICmp(
+                self.bundle.builder,
+                LLVMIntPredicate::LLVMIntEQ,
+                value_call_tmp,
+                value_zero,
+                name_icmp.as_ptr() as *const c_char
+            );
+            let _ = LLVMBuildCondBr(self.bundle.builder, value_icmp, bb_match, bb_next);
+        };
     }
 
-    fn make_body_block(&mut self) -> LLVMBasicBlockRef {
+    fn make_block(&mut self, name: &str) -> LLVMBasicBlockRef {
         let f = self.bundle.f.unwrap();
+        let name_block = ModuleBundle::value_name(name);
         unsafe {
             let bb = LLVMAppendBasicBlockInContext(
                 self.bundle.context,
                 f,
-                ModuleBundle::value_name("body").as_ptr() as *const c_char,
+                name_block.as_ptr() as *const c_char
             );
             LLVMPositionBuilderAtEnd(self.bundle.builder, bb);
             bb
@@ -181,7 +289,8 @@ impl <'a, 'b> MainGen<'a, 'b> {
         bb_body: LLVMBasicBlockRef,
         bb_ret: LLVMBasicBlockRef,
         callee_sig: &'a FunctionSignature,
-        callee_values: &[LLVMValueRef]
+        callee_values: &[LLVMValueRef],
+        base: usize,
     ) -> () {
         unsafe { LLVMPositionBuilderAtEnd(self.bundle.builder, bb_body); }
         let name_retval = ModuleBundle::value_name(NAME_RETVAL);
@@ -191,7 +300,7 @@ impl <'a, 'b> MainGen<'a, 'b> {
         let value_errcode = self.bundle.get_constint(self.bundle.t_i32, 0);
         for i in 1..callee_values.len() {
             let value_store = callee_values.get(i).unwrap();
-            self.argv_to_atoll(*value_store, i as i32);
+            self.argv_to_atoll(*value_store, (base + i - 1) as i32);
         }
         let value_result = self.gen_call_calcc_main(callee_sig, callee_values);
         let name_load_tmp = self.bundle.scope.next_value_name();
@@ -229,38 +338,29 @@ impl <'a, 'b> MainGen<'a, 'b> {
         }
     }
 
+    /// Returns the stack values (i.e., the result followed by the arguments) for each callee.
     fn gen_entry_stack(
         &mut self,
         bb_entry: LLVMBasicBlockRef,
-        f_sig: &'a FunctionSignature
-    ) -> Vec<LLVMValueRef> {
+        f_sigs: &'a [FunctionSignature]
+    ) -> Vec<Vec<LLVMValueRef>> {
         unsafe { LLVMPositionBuilderAtEnd(self.bundle.builder, bb_entry); }
         let _value_ret = self.bundle.gen_alloca(NAME_RETVAL, self.bundle.t_i32);
         let _value_argc = self.bundle.gen_alloca(NAME_ARGC, self.bundle.t_i32);
         let _value_argv = self.bundle.gen_alloca(NAME_ARGV, self.bundle.t_opaque);
-        let name_result = self.bundle.scope.next_value_name();
-        let value_result = self.bundle.gen_alloca(&name_result, f_sig.t_ret);
-        let mut v: Vec<LLVMValueRef> = vec![value_result];
-        for t in f_sig.params.iter() {
-            let value_name = self.bundle.scope.next_value_name();
-            let value_param = self.bundle.gen_alloca(&value_name, *t);
-            v.push(value_param);
-        }
-        v
-    }
-
-    fn make_ret_block(&mut self) -> LLVMBasicBlockRef {
-        let f = self.bundle.f.unwrap();
-        let name_block = ModuleBundle::value_name("ret_label");
-        unsafe {
-            let bb = LLVMAppendBasicBlockInContext(
-                self.bundle.context,
-                f,
-                name_block.as_ptr() as *const c_char
-            );
-            LLVMPositionBuilderAtEnd(self.bundle.builder, bb);
-            bb
+        let mut values: Vec<Vec<LLVMValueRef>> = Vec::new();
+        for f_sig in f_sigs {
+            let name_result = self.bundle.scope.next_value_name();
+            let value_result = self.bundle.gen_alloca(&name_result, f_sig.t_ret);
+            let mut v: Vec<LLVMValueRef> = vec![value_result];
+            for t in f_sig.params.iter() {
+                let value_name = self.bundle.scope.next_value_name();
+                let value_param = self.bundle.gen_alloca(&value_name, *t);
+                v.push(value_param);
+            }
+            values.push(v);
         }
+        values
     }
 
     fn gen_ret(&mut self, bb_ret: LLVMBasicBlockRef) -> () {
@@ -279,27 +379,28 @@ impl <'a, 'b> MainGen<'a, 'b> {
         }
     }
 
+    /// Prints the error message `name_err` (formatted with `err_args`) and the usage to stderr.
     fn gen_err_block(
         &mut self,
         bb_err: LLVMBasicBlockRef,
         bb_ret: LLVMBasicBlockRef,
-        callee_arg_len: usize
+        name_err: &str,
+        err_args: &[LLVMValueRef],
     ) -> () {
         unsafe { LLVMPositionBuilderAtEnd(self.bundle.builder, bb_err) };
         let name_retval = ModuleBundle::value_name(NAME_RETVAL);
         let name_stderr = ModuleBundle::value_name(NAME_STDERR);
         let name_fprintf = ModuleBundle::value_name(NAME_FPRINTF);
-        let name_arg_err = ModuleBundle::value_name(NAME_ARG_ERR);
+        let name_err = ModuleBundle::value_name(name_err);
         let name_usage = ModuleBundle::value_name(NAME_USAGE);
         let value_retval = self.bundle.get_value(&name_retval);
         let value_stderr = self.bundle.get_value(&name_stderr);
         let value_fprintf = self.bundle.get_value(&name_fprintf);
-        let value_arg_err = self.bundle.get_value(&name_arg_err);
+        let value_err = self.bundle.get_value(&name_err);
         let value_usage = self.bundle.get_value(&name_usage);
         let name_stderr_tmp = self.bundle.scope.next_value_name();
         let name_call_tmp1 = self.bundle.scope.next_value_name();
         let name_call_tmp2 = self.bundle.scope.next_value_name();
-        let value_num_argc = self.bundle.get_constint(self.bundle.t_i32, callee_arg_len as i64);
         let value_errcode = self.bundle.get_constint(self.bundle.t_i32, 1);
         let value_stderr_tmp = unsafe { LLVMBuildLoad2(
             self.bundle.builder,
@@ -307,7 +408,8 @@ impl <'a, 'b> MainGen<'a, 'b> {
             value_stderr,
             name_stderr_tmp.as_ptr() as *const c_char
         )};
-        let mut params_fprintf1: Vec<LLVMValueRef> = vec![value_stderr_tmp, value_arg_err, value_num_argc];
+        let mut params_fprintf1: Vec<LLVMValueRef> = vec![value_stderr_tmp, value_err];
+        params_fprintf1.extend_from_slice(err_args);
         let mut params_fprintf2: Vec<LLVMValueRef> = vec![value_stderr_tmp, value_usage];
         let mut param_types: Vec<LLVMTypeRef> = vec![self.bundle.t_opaque, self.bundle.t_opaque];
         unsafe {
@@ -339,17 +441,45 @@ impl <'a, 'b> MainGen<'a, 'b> {
     }
 
     fn argv_to_atoll(&mut self, value_store: LLVMValueRef, idx: i32) -> () {
-        let name_argv = ModuleBundle::value_name(NAME_ARGV);
         let name_atoll = ModuleBundle::value_name(NAME_ATOLL);
         let value_atoll = self.bundle.get_value(&name_atoll);
+        let value_arg = self.gen_load_argv(idx);
+        let name_call_tmp = self.bundle.scope.next_value_name();
+        let mut params_atoll: Vec<LLVMValueRef> = vec![value_arg];
+        let mut param_types: Vec<LLVMTypeRef> = vec![self.bundle.t_opaque];
+        unsafe {
+            let t_f = LLVMFunctionType(
+                self.bundle.t_i64,
+                param_types.as_mut_ptr(),
+                param_types.len() as u32,
+                false as LLVMBool
+            );
+            let value_call_tmp = LLVMBuildCall2(
+                self.bundle.builder,
+                t_f,
+                value_atoll,
+                params_atoll.as_mut_ptr(),
+                params_atoll.len() as c_uint,
+                name_call_tmp.as_ptr() as *const c_char
+            );
+            let _ = LLVMBuildStore(
+                self.bundle.builder,
+                value_call_tmp,
+                value_store
+            );
+        }
+    }
+
+    /// Returns the value of `argv[idx]` for the arguments of main.
+    fn gen_load_argv(&mut self, idx: i32) -> LLVMValueRef {
+        let name_argv = ModuleBundle::value_name(NAME_ARGV);
         let value_argv = self.bundle.get_value(&name_argv);
         let value_idx = self.bundle.get_constint(self.bundle.t_i32, idx as i64);
         let name_load_tmp1 = self.bundle.scope.next_value_name();
         let name_gep_tmp = self.bundle.scope.next_value_name();
         let name_load_tmp2 = self.bundle.scope.next_value_name();
-        let name_call_tmp = self.bundle.scope.next_value_name();
         let mut indices: Vec<LLVMValueRef> = vec![value_idx];
-        let value_load_tmp2 = unsafe {
+        unsafe {
             let value_load_tmp1 = LLVMBuildLoad2(
                 self.bundle.builder,
                 self.bundle.t_opaque,
@@ -370,29 +500,6 @@ impl <'a, 'b> MainGen<'a, 'b> {
                 value_gep_tmp,
                 name_load_tmp2.as_ptr() as *const c_char
             )
-        };
-        let mut params_atoll: Vec<LLVMValueRef> = vec![value_load_tmp2];
-        let mut param_types: Vec<LLVMTypeRef> = vec![self.bundle.t_opaque];
-        unsafe {
-            let t_f = LLVMFunctionType(
-                self.bundle.t_i64,
-                param_types.as_mut_ptr(),
-                param_types.len() as u32,
-                false as LLVMBool
-            );
-            let value_call_tmp = LLVMBuildCall2(
-                self.bundle.builder,
-                t_f,
-                value_atoll,
-                params_atoll.as_mut_ptr(),
-                params_atoll.len() as c_uint,
-                name_call_tmp.as_ptr() as *const c_char
-            );
-            let _ = LLVMBuildStore(
-                self.bundle.builder,
-                value_call_tmp,
-                value_store
-            );
         }
     }
 
@@ -401,7 +508,7 @@ impl <'a, 'b> MainGen<'a, 'b> {
         callee_sig: &'a FunctionSignature,
         callee_values: &[LLVMValueRef]
     ) -> LLVMValueRef {
-        let name_calcc = ModuleBundle::value_name(NAME_CALCC_MAIN);
+        let name_calcc = ModuleBundle::value_name(&callee_sig.name);
         let value_calcc = self.bundle.get_value(&name_calcc);
         let value_result = callee_values.first().unwrap();
         let mut args: Vec<LLVMValueRef> = Vec::new();
@@ -450,13 +557,28 @@ impl <'a, 'b> MainGen<'a, 'b> {
         unsafe { LLVMSetAlignment(value, align as c_uint); }
     }
 
-    fn declare_global_strings(&mut self) -> () {
+    fn declare_global_strings(&mut self, string_usage: &str) -> () {
         let _value_argerr: LLVMValueRef = self.bundle.declare_global_string(NAME_ARG_ERR, STRING_ARG_ERR);
         let _value_result_str: LLVMValueRef = self.bundle.declare_global_string(
             NAME_RESULT_STR,
             STRING_RESULT_STR
         );
-        let _value_usage: LLVMValueRef = self.bundle.declare_global_string(NAME_USAGE, STRING_USAGE);
+        let _value_usage: LLVMValueRef = self.bundle.declare_global_string(NAME_USAGE, string_usage);
+    }
+
+    /// Declares the name of each kernel (for dispatch), and the error message listing them.
+    fn declare_kernel_strings(&mut self, f_sigs: &'a [FunctionSignature]) -> () {
+        let mut names: Vec<&str> = Vec::new();
+        for f_sig in f_sigs {
+            let name = format!("{}{}", PREFIX_KERNEL_STR, f_sig.name);
+            let _value_kernel_str = self.bundle.declare_global_string(&name, &format!("{}\0", f_sig.name));
+            names.push(&f_sig.name);
+        }
+        let string_kernel_err = format!(
+            "Expected the name of a kernel as the first arg to main (one of: {})\n\0",
+            names.join(", ")
+        );
+        let _value_kernel_err = self.bundle.declare_global_string(NAME_KERNEL_ERR, &string_kernel_err);
     }
 
     fn declare_stderr(&mut self) -> LLVMValueRef {
@@ -481,7 +603,7 @@ impl <'a, 'b> MainGen<'a, 'b> {
     }
 
     fn declare_calcc_main(&mut self, f_sig: &'a FunctionSignature) -> LLVMValueRef {
-        let name: String = ModuleBundle::value_name(NAME_CALCC_MAIN);
+        let name: String = ModuleBundle::value_name(&f_sig.name);
         let mut params = f_sig.params.clone();
         self.bundle.emit_declaration(&name, f_sig.t_ret, &mut params, false)
     }
@@ -492,6 +614,12 @@ impl <'a, 'b> MainGen<'a, 'b> {
         self.bundle.emit_declaration(&name, self.bundle.t_i32, &mut params, true)
     }
 
+    fn declare_strcmp(&mut self) -> LLVMValueRef {
+        let name: String = ModuleBundle::value_name(NAME_STRCMP);
+        let mut params: Vec<LLVMTypeRef> = vec![self.bundle.t_opaque, self.bundle.t_opaque];
+        self.bundle.emit_declaration(&name, self.bundle.t_i32, &mut params, false)
+    }
+
     fn declare_fprintf(&mut self) -> LLVMValueRef {
         let name: String = ModuleBundle::value_name(NAME_FPRINTF);
         let mut params: Vec<LLVMTypeRef> = vec![self.bundle.t_opaque, self.bundle.t_opaque];
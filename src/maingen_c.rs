@@ -1,8 +1,10 @@
 // Copyright 2024, Giordano Salvador
 // SPDX-License-Identifier: BSD-3-Clause
 
-/// Generate LLVM IR for a main function, using the C standard library, from `main.c.template`.
-/// Enable this using `--c-main`/`-C`.
+//! Generate LLVM IR for a main function, using the C standard library, from `main.c.template`.
+//! Enable this using `--c-main`/`-C`.
+//! If several kernels are compiled into the module, the main function is generated from
+//! `main_dispatch.c.template` instead, and dispatches to the kernel named by its first argument.
 
 extern crate llvm_sys as llvm;
 use llvm::prelude::LLVMTypeRef;
@@ -23,12 +25,17 @@ use module::ModuleBundle;
 
 pub struct MainGenC {}
 
+const INPUT_DISPATCH_LIST   : &str = "@@DISPATCH_LIST";
+const INPUT_ENTRY_DECLS_LIST: &str = "@@ENTRY_DECLS_LIST";
+const INPUT_ENTRY_NAME      : &str = "@@ENTRY_NAME";
+const INPUT_KERNEL_NAMES    : &str = "@@KERNEL_NAMES";
 const INPUT_NUM_ARGS        : &str = "@@NUM_ARGS";
 const INPUT_USAGE_ARGS      : &str = "@@USAGE_ARGS";
 const INPUT_PARAM_TYPES_LIST: &str = "@@PARAM_TYPES_LIST";
 const INPUT_PARAM_DECLS_LIST: &str = "@@PARAM_DECLS_LIST";
 const INPUT_PARAMS_LIST     : &str = "@@PARAMS_LIST";
 const MAIN_C_TEMPLATE       : &str = include_str!("main.c.template");
+const MAIN_DISPATCH_C_TEMPLATE: &str = include_str!("main_dispatch.c.template");
 
 const CLANG_AGS: [&str; 9] = [
     "-x", "c", "-", "-Wall", "-Werror", "-std=c99", "-fPIC", "-c", "-o"
@@ -38,9 +45,17 @@ impl MainGenC {
     /// Returns a path (option) to the object file generated from `main.c.tempalte`.
     /// This object file will be linked to the object file generated from the LLVM during the
     /// IRGen stage.
-    pub fn gen(callee_sig: &FunctionSignature, stem: &str, verbose: bool) -> Option<String> {
-        let body = Self::substitute_all_inputs_in_body(MAIN_C_TEMPLATE, callee_sig, verbose);
+    /// If `triple` is not empty, the object file is compiled for that target triple.
+    pub fn gen(callee_sigs: &[FunctionSignature], stem: &str, triple: &str, verbose: bool) -> Option<String> {
+        let body = match callee_sigs {
+            [callee_sig]    => Self::substitute_all_inputs_in_body(MAIN_C_TEMPLATE, callee_sig, verbose),
+            _               => Self::substitute_all_inputs_in_dispatch_body(MAIN_DISPATCH_C_TEMPLATE, callee_sigs, verbose),
+        };
         let mut clang_args: Vec<&str> = Vec::from(CLANG_AGS);
+        let target_arg = format!("--target={}", triple);
+        if !triple.is_empty() {
+            clang_args.insert(0, target_arg.as_str());
+        }
         let dir = env::temp_dir();
         let main_obj_path = Self::get_main_obj_path(&dir, stem);
         if verbose {
@@ -48,8 +63,8 @@ impl MainGenC {
         }
         clang_args.push(main_obj_path.as_str());
         let clang_result = Command::run_with_input("clang", &clang_args, &body);
-        if clang_result.stdout.is_some() {
-            println!("{}", clang_result.stdout.unwrap());
+        if let Some(stdout) = clang_result.stdout {
+            println!("{}", stdout);
         }
         if clang_result.success {
             Some(main_obj_path)
@@ -74,7 +89,10 @@ impl MainGenC {
         let param_decls = Self::collect_callee_param_decls_string(&callee_sig.params, 1);
         let param_types_list = Self::get_callee_param_types_list_string(&callee_sig.params);
         let params_list = Self::get_callee_params_list_string(&callee_sig.params);
-        let body_with_num_args = Self::substitute_param_in_string(body, INPUT_NUM_ARGS, &num_args);
+        let body_with_entry_name = Self::substitute_param_in_string(body, INPUT_ENTRY_NAME, &callee_sig.name);
+        let body_with_num_args = Self::substitute_param_in_string(
+            &body_with_entry_name, INPUT_NUM_ARGS, &num_args
+        );
         let body_with_usage_args = Self::substitute_param_in_string(
             &body_with_num_args, INPUT_USAGE_ARGS, &usage_string
         );
@@ -93,6 +111,61 @@ impl MainGenC {
         body_with_params_list
     }
 
+    fn substitute_all_inputs_in_dispatch_body(
+        body: &str,
+        callee_sigs: &[FunctionSignature],
+        verbose: bool,
+    ) -> String {
+        let kernel_names: Vec<&str> = callee_sigs.iter().map(|callee_sig| callee_sig.name.as_str()).collect();
+        let entry_decls: Vec<String> = callee_sigs.iter().map(|callee_sig| format!(
+            "extern t_i64 {}({});",
+            callee_sig.name,
+            Self::get_callee_param_types_list_string(&callee_sig.params)
+        )).collect();
+        let dispatch: Vec<String> = callee_sigs.iter().map(Self::get_dispatch_string).collect();
+        let body_with_kernel_names = Self::substitute_param_in_string(
+            body, INPUT_KERNEL_NAMES, &kernel_names.join(", ")
+        );
+        let body_with_entry_decls = Self::substitute_param_in_string(
+            &body_with_kernel_names, INPUT_ENTRY_DECLS_LIST, &entry_decls.join("\n")
+        );
+        let body_with_dispatch = Self::substitute_param_in_string(
+            &body_with_entry_decls, INPUT_DISPATCH_LIST, &dispatch.join("\n    ")
+        );
+        if verbose {
+            eprintln!("Body of 'main.c' after input substitution:\n{}", body_with_dispatch);
+        }
+        body_with_dispatch
+    }
+
+    /// Generates the statement calling the callee if the first argument is its name (i.e., the
+    /// body of `main.c.template` for the callee, with the arguments following the name).
+    fn get_dispatch_string(callee_sig: &FunctionSignature) -> String {
+        let n = callee_sig.params.len();
+        let mut lines: Vec<String> = vec![
+            format!("if (strcmp(argv[1], \"{}\") == 0) {{", callee_sig.name),
+            format!("    if (argc != BASE + {}) {{", n),
+            format!("        (void) fprintf(stderr, \"Invalid number of args to main. Expected %d args\\n\", {});", n),
+            "        (void) fprintf(stderr, USAGE);".to_string(),
+            "        return 1;".to_string(),
+            "    }".to_string(),
+        ];
+        if n > 0 {
+            lines.push(format!("    {}", Self::collect_callee_param_decls_string(&callee_sig.params, 2)));
+        }
+        lines.append(&mut vec![
+            format!(
+                "    const t_i64 result = {}({});",
+                callee_sig.name,
+                Self::get_callee_params_list_string(&callee_sig.params)
+            ),
+            "    (void) printf(\"calcc_main result: %lld\\n\", result);".to_string(),
+            "    return 0;".to_string(),
+            "}".to_string(),
+        ]);
+        lines.join("\n    ")
+    }
+
     fn get_usage_args_string(params: &[LLVMTypeRef]) -> String {
         let mut args_string = String::new();
         for i in 0..params.len() {
@@ -5,6 +5,7 @@ extern crate llvm_sys as llvm;
 
 use llvm::analysis::LLVMVerifierFailureAction;
 use llvm::analysis::LLVMVerifyModule;
+use llvm::bit_reader::LLVMParseBitcodeInContext2;
 use llvm::bit_writer::LLVMWriteBitcodeToFD;
 use llvm::bit_writer::LLVMWriteBitcodeToFile;
 use llvm::core::LLVMAddFunction;
@@ -14,25 +15,37 @@ use llvm::core::LLVMConstInt;
 use llvm::core::LLVMContextCreate;
 use llvm::core::LLVMContextDispose;
 use llvm::core::LLVMCreateBuilderInContext;
+use llvm::core::LLVMCreateMemoryBufferWithContentsOfFile;
 use llvm::core::LLVMDisposeBuilder;
+use llvm::core::LLVMDisposeMemoryBuffer;
 use llvm::core::LLVMDisposeMessage;
 use llvm::core::LLVMDisposeModule;
 use llvm::core::LLVMFunctionType;
+use llvm::core::LLVMGetFirstFunction;
 use llvm::core::LLVMGetIntTypeWidth;
+use llvm::core::LLVMGetIntrinsicID;
+use llvm::core::LLVMGetNamedFunction;
+use llvm::core::LLVMGetNextFunction;
 use llvm::core::LLVMGetTypeKind;
+use llvm::core::LLVMGetValueName2;
+use llvm::core::LLVMGlobalGetValueType;
 use llvm::core::LLVMInt32TypeInContext;
 use llvm::core::LLVMInt64TypeInContext;
+use llvm::core::LLVMIsDeclaration;
 use llvm::core::LLVMModuleCreateWithNameInContext;
 use llvm::core::LLVMPointerTypeInContext;
 use llvm::core::LLVMPrintModuleToString;
+use llvm::core::LLVMPrintTypeToString;
 use llvm::core::LLVMSetSourceFileName;
 use llvm::linker::LLVMLinkModules2;
 use llvm::prelude::LLVMBool;
 use llvm::prelude::LLVMBuilderRef;
 use llvm::prelude::LLVMContextRef;
+use llvm::prelude::LLVMMemoryBufferRef;
 use llvm::prelude::LLVMModuleRef;
 use llvm::prelude::LLVMTypeRef;
 use llvm::prelude::LLVMValueRef;
+use llvm::target_machine::LLVMCodeGenFileType;
 use llvm::LLVMTypeKind;
 
 use std::collections::HashMap;
@@ -48,10 +61,12 @@ use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::ptr;
+use std::rc::Rc;
 
 use crate::command;
 use crate::exit_code;
 use crate::options;
+use crate::target;
 
 use command::Command;
 use exit_code::exit;
@@ -59,16 +74,18 @@ use exit_code::ExitCode;
 use options::CodeGenType;
 use options::OutputType;
 use options::RunOptions;
+use target::TargetMachine;
 
 #[derive(Clone)]
 pub struct FunctionSignature {
+    pub name:   String,
     pub t_ret:  LLVMTypeRef,
     pub params: Vec<LLVMTypeRef>,
 }
 
 impl FunctionSignature {
-    pub fn new(t_ret: LLVMTypeRef, params: Vec<LLVMTypeRef>) -> Self {
-        FunctionSignature{t_ret, params}
+    pub fn new(name: &str, t_ret: LLVMTypeRef, params: Vec<LLVMTypeRef>) -> Self {
+        FunctionSignature{name: name.to_string(), t_ret, params}
     }
 }
 
@@ -80,6 +97,7 @@ pub struct ModuleBundle<'a> {
     pub module:         LLVMModuleRef,
     pub name:           &'a String,
     pub objects:        Vec<String>,
+    pub owner:          Rc<ContextOwner>,
     pub scope:          Scope,
     pub t_i32:          LLVMTypeRef,
     pub t_i64:          LLVMTypeRef,
@@ -89,8 +107,20 @@ pub struct ModuleBundle<'a> {
 
 impl <'a> ModuleBundle<'a> {
     pub fn new(name: &'a String, verbose: bool) -> Self {
+        let owner = Rc::new(ContextOwner(unsafe { LLVMContextCreate() }));
+        Self::new_with_context(name, owner, verbose)
+    }
+
+    /// Creates a bundle for a new module in the context of `other`, so that types and values of
+    /// the two modules can be mixed (e.g., for declaring the kernel of `other` before linking).
+    /// The context is shared, so it is disposed only after both bundles are dropped.
+    pub fn new_in_context_of(name: &'a String, other: &ModuleBundle, verbose: bool) -> Self {
+        Self::new_with_context(name, Rc::clone(&other.owner), verbose)
+    }
+
+    fn new_with_context(name: &'a String, owner: Rc<ContextOwner>, verbose: bool) -> Self {
+        let context = owner.0;
         let bundle = unsafe {
-            let context = LLVMContextCreate();
             let n = Self::value_name(name.as_str());
             let module = LLVMModuleCreateWithNameInContext(n.as_ptr() as *const c_char, context);
             let builder = LLVMCreateBuilderInContext(context);
@@ -102,6 +132,7 @@ impl <'a> ModuleBundle<'a> {
                 module,
                 name,
                 objects: Vec::new(),
+                owner,
                 scope: Scope::new(),
                 t_i32: LLVMInt32TypeInContext(context),
                 t_i64: LLVMInt64TypeInContext(context),
@@ -171,18 +202,148 @@ impl <'a> ModuleBundle<'a> {
         }
     }
 
+    /// Links the module of `other` into this module.
+    /// Both modules must be in the same context, and the module of `other` is destroyed by linking.
     pub fn link_into(&mut self, other: &mut ModuleBundle) -> bool {
+        if self.context != other.context {
+            eprintln!("Cannot link modules from different contexts");
+            return false;
+        }
+        let source = match other.f_sig {
+            Some(ref f_sig) => format!("kernel '{}'", f_sig.name),
+            None            => format!("module '{}'", other.name),
+        };
+        let mut declarations = self.get_declarations();
+        declarations.append(&mut other.get_declarations());
         let result: LLVMBool = unsafe { LLVMLinkModules2 (
             self.module,
             other.module,
         )};
-        result == false as LLVMBool
+        other.module = ptr::null_mut();
+        result == false as LLVMBool && self.check_declarations(declarations, &source)
+    }
+
+    /// Links the module read from the bitcode file `f` into this module (with `--link-bitcode`).
+    /// The bitcode is read in the context of this module, so that it can be linked.
+    pub fn link_bitcode_file(&mut self, f: &str) -> bool {
+        let name = format!("{}\0", f);
+        let mut buffer: LLVMMemoryBufferRef = ptr::null_mut();
+        let mut error_ptr: *mut c_char = ptr::null_mut();
+        let result: LLVMBool = unsafe {
+            LLVMCreateMemoryBufferWithContentsOfFile(
+                name.as_ptr() as *const c_char,
+                &mut buffer as *mut LLVMMemoryBufferRef,
+                &mut error_ptr as *mut *mut c_char,
+            )
+        };
+        if result != false as LLVMBool {
+            unsafe {
+                let c_string = CStr::from_ptr(error_ptr as *const c_char);
+                eprintln!("Failed to read bitcode file '{}': {}", f, c_string.to_string_lossy());
+                LLVMDisposeMessage(error_ptr);
+            }
+            return false;
+        }
+        let mut module: LLVMModuleRef = ptr::null_mut();
+        let result: LLVMBool = unsafe {
+            let result = LLVMParseBitcodeInContext2(self.context, buffer, &mut module as *mut LLVMModuleRef);
+            LLVMDisposeMemoryBuffer(buffer);
+            result
+        };
+        if result != false as LLVMBool {
+            eprintln!("Failed to parse bitcode file '{}'", f);
+            return false;
+        }
+        if self.verbose {
+            eprintln!("Linking bitcode file '{}'", f);
+        }
+        let declarations = self.get_declarations();
+        // The module read from the bitcode is destroyed by linking
+        let result: LLVMBool = unsafe { LLVMLinkModules2(self.module, module) };
+        if result != false as LLVMBool {
+            eprintln!("Failed to link bitcode file '{}'", f);
+            return false;
+        }
+        self.check_declarations(declarations, &format!("bitcode file '{}'", f))
+    }
+
+    /// Checks that each of the `declarations` (of the modules linked from `source`) still has its
+    /// function type in this module.
+    /// The linker does not check that a definition matches the type of the declaration, nor that
+    /// two declarations have the same type (the calls would then pass the wrong arguments).
+    fn check_declarations(&self, declarations: Vec<(String, LLVMTypeRef)>, source: &str) -> bool {
+        for (name, t) in declarations {
+            let n = Self::value_name(&name);
+            let f_linked = unsafe { LLVMGetNamedFunction(self.module, n.as_ptr() as *const c_char) };
+            if f_linked.is_null() {
+                eprintln!("Extern function '{}' is not a function in {}", name, source);
+                return false;
+            }
+            let t_linked = unsafe { LLVMGlobalGetValueType(f_linked) };
+            if t_linked != t {
+                eprintln!(
+                    "Extern function '{}' of type '{}' is linked with type '{}' from {}",
+                    name,
+                    Self::type_to_string(t),
+                    Self::type_to_string(t_linked),
+                    source
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the names and function types of the functions declared (but not defined) in this
+    /// module, other than intrinsics (i.e., the extern functions of the kernels).
+    fn get_declarations(&self) -> Vec<(String, LLVMTypeRef)> {
+        let mut declarations: Vec<(String, LLVMTypeRef)> = Vec::new();
+        unsafe {
+            let mut f = LLVMGetFirstFunction(self.module);
+            while !f.is_null() {
+                if LLVMIsDeclaration(f) != false as LLVMBool && LLVMGetIntrinsicID(f) == 0 {
+                    let mut len: usize = 0;
+                    let name_ptr = LLVMGetValueName2(f, &mut len as *mut usize);
+                    let name = CStr::from_ptr(name_ptr).to_string_lossy().to_string();
+                    declarations.push((name, LLVMGlobalGetValueType(f)));
+                }
+                f = LLVMGetNextFunction(f);
+            }
+        }
+        declarations
+    }
+
+    fn type_to_string(t: LLVMTypeRef) -> String {
+        unsafe {
+            let string_ptr = LLVMPrintTypeToString(t);
+            let string = CStr::from_ptr(string_ptr).to_string_lossy().to_string();
+            LLVMDisposeMessage(string_ptr);
+            string
+        }
     }
 
     pub fn value_name(s: &str) -> String {
         String::from(s) + "\0"
     }
 
+    /// Mangles identifiers containing non-ASCII characters into names that print unquoted in
+    /// LLVM IR (e.g., "café" becomes "caf$uE9$").
+    /// Calc identifiers cannot contain '$', so mangled names never collide with ASCII identifiers.
+    pub fn mangle_name(s: &str) -> String {
+        if s.is_ascii() {
+            return String::from(s);
+        }
+        let mut name = String::new();
+        for c in s.chars() {
+            if c.is_ascii() {
+                name.push(c);
+            } else {
+                name += format!("$u{:X}$", c as u32).as_str();
+            }
+        }
+        name
+    }
+
     pub fn verify_module(&self) -> bool {
         let mut error_ptr: *mut c_char = ptr::null_mut();
         let result: LLVMBool = unsafe {
@@ -258,23 +419,40 @@ impl <'a> ModuleBundle<'a> {
         }.to_string()
     }
 
-    /// Objects required for linking the final object file/executable should be pushed before
-    /// output
+    /// Objects (or static archives) required for linking the final object file/executable should
+    /// be pushed before output
     pub fn push_object(&mut self, obj_path: String) -> () {
         let path = Path::new(&obj_path);
-        if !path.is_file() || ".o" == path.extension().unwrap() {
-            eprintln!("Expected object file '{}'", obj_path);
+        let is_object = matches!(path.extension().and_then(|ext| ext.to_str()), Some("o") | Some("a"));
+        if !path.is_file() || !is_object {
+            eprintln!("Expected object file or archive '{}'", obj_path);
             exit(ExitCode::ModuleError);
         }
         self.objects.push(obj_path);
     }
 
-    pub fn write_module(&mut self, options: &RunOptions, output: &OutputType) -> bool {
+    pub fn write_module(
+        &mut self,
+        machine: &TargetMachine,
+        options: &RunOptions,
+        output: &OutputType,
+        kernels: &[(&str, &str)],
+    ) -> bool {
+        let annotation: Option<&[(&str, &str)]> = if options.asm_source {
+            Some(kernels)
+        } else {
+            None
+        };
         match *output {
             OutputType::Stdout  => {
                 match options.codegen_type {
                     CodeGenType::Llvmir     => println!("{}", self),
                     CodeGenType::Bitcode    => self.write_bitcode_to_file("-"),
+                    CodeGenType::Assembly   => self.write_assembly(machine, output, annotation),
+                    CodeGenType::Object     => if !machine.emit_to_stdout(self, LLVMCodeGenFileType::LLVMObjectFile) {
+                        eprintln!("Failed to write object file to Stdout");
+                        exit(ExitCode::WriteError);
+                    },
                     _                       => {
                         eprintln!("Unexpected  '{}' for output to stdout", options.codegen_type);
                         return false;
@@ -283,44 +461,26 @@ impl <'a> ModuleBundle<'a> {
             },
             OutputType::File(f) => {
                 match options.codegen_type {
-                    CodeGenType::Llvmir     => {
-                        let string: String = self.to_string();
-                        let mut file = match File::create(f) {
-                            Ok(file)    => file,
-                            Err(msg)    => {
-                                eprintln!("Failed to open output file '{}': {}", f, msg);
-                                return false;
-                            }
-                        };
-                        match file.write_all(string.as_bytes()) {
-                            Ok(())      => (),
-                            Err(msg)    => {
-                                eprintln!("Failed to write to output file '{}': {}", f, msg);
-                                return false;
-                            }
-                        }
+                    CodeGenType::Llvmir     => if !Self::write_string_to_file(f, &self.to_string()) {
+                        return false;
                     },
                     CodeGenType::Bitcode    => self.write_bitcode_to_file(f),
-                    CodeGenType::Object     => {
-                        let f_path = Path::new(f);
-                        let temp_dir = env::temp_dir();
-                        let f_stem = f_path.file_stem().unwrap().to_str().unwrap();
-                        let f_bc = temp_dir.join(format!("{}.bc", f_stem));
-                        let f_bc_str = f_bc.to_str().unwrap();
-                        self.write_bitcode_to_file(f_bc_str);
-                        self.object_file_from_bitcode(f, f_bc_str);
-                    },
-                    CodeGenType::Executable => {
+                    CodeGenType::Assembly   => self.write_assembly(machine, output, annotation),
+                    CodeGenType::Object     => self.write_object_file(machine, f),
+                    CodeGenType::Executable |
+                    CodeGenType::SharedLib  => {
                         let f_path = Path::new(f);
                         let temp_dir = env::temp_dir();
                         let f_stem = f_path.file_stem().unwrap().to_str().unwrap();
-                        let f_bc = temp_dir.join(format!("{}.bc", f_stem));
-                        let f_bc_str = f_bc.to_str().unwrap();
-                        self.write_bitcode_to_file(f_bc_str);
                         let f_obj = temp_dir.join(format!("{}.obj", f_stem));
                         let f_obj_str = f_obj.to_str().unwrap();
-                        self.object_file_from_bitcode(f_obj_str, f_bc_str);
-                        self.executable_file_from_object(f, f_obj_str);
+                        let shared = options.codegen_type == CodeGenType::SharedLib;
+                        self.write_object_file(machine, f_obj_str);
+                        self.link_file_from_object(f, f_obj_str, &options.target_triple, shared, &options.link_args);
+                    },
+                    CodeGenType::Archive    => {
+                        eprintln!("Cannot write a single module as an archive");
+                        exit(ExitCode::WriteError);
                     },
                     CodeGenType::Unset      => {
                         eprintln!("Cannot write module with unset codegen type");
@@ -332,31 +492,138 @@ impl <'a> ModuleBundle<'a> {
         true
     }
 
-    fn object_file_from_bitcode(&self, f_obj: &str, f_bc: &str) -> () {
-        let arg_output = format!("-o={}", f_obj);
-        let args: Vec<&str> = vec![
-            "-relocation-model=pic",
-            "-filetype=obj",
-            arg_output.as_str(),
-            f_bc,
-        ];
-        let result_llc = Command::run("llc", &args);
-        if !result_llc.success {
-            eprintln!("Failed to write object file '{}' from bitcode file '{}'", f_obj, f_bc);
+    fn write_string_to_file(f: &str, string: &str) -> bool {
+        let mut file = match File::create(f) {
+            Ok(file)    => file,
+            Err(msg)    => {
+                eprintln!("Failed to open output file '{}': {}", f, msg);
+                return false;
+            }
+        };
+        match file.write_all(string.as_bytes()) {
+            Ok(())      => true,
+            Err(msg)    => {
+                eprintln!("Failed to write to output file '{}': {}", f, msg);
+                false
+            }
+        }
+    }
+
+    /// Inserts the source line of each `.loc` directive (i.e., of each row of the line table) in the
+    /// assembly as a comment, for the kernels given by `(source, entry_name)` in `kernels`.
+    /// The source of a kernel is used from its label up to the label of the next kernel, and a line
+    /// is only inserted again if the line of the source changes.
+    fn annotate_assembly(asm: &str, kernels: &[(&str, &str)], comment: &str) -> String {
+        let mut result = String::with_capacity(asm.len());
+        let mut source_lines: Option<Vec<&str>> = None;
+        let mut prev_line: usize = 0;
+        for line in asm.lines() {
+            let label = line.trim_end().strip_suffix(':').unwrap_or_default();
+            let kernel = kernels.iter().find(|(_, entry_name)| {
+                label == *entry_name || label.strip_prefix('_') == Some(*entry_name)
+            });
+            if let Some((source, _)) = kernel {
+                source_lines = Some(source.lines().collect());
+                prev_line = 0;
+            }
+            let mut fields = line.split_whitespace();
+            let loc_line: Option<usize> = match (fields.next(), fields.next(), fields.next()) {
+                (Some(".loc"), Some(_), Some(n))    => n.parse::<usize>().ok(),
+                _                                   => None,
+            };
+            if let (Some(lines), Some(n)) = (&source_lines, loc_line) {
+                if n != prev_line && n > 0 && n <= lines.len() {
+                    result += format!("{} calc:{}: {}\n", comment, n, lines[n - 1].trim()).as_str();
+                    prev_line = n;
+                }
+            }
+            result += line;
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Writes the assembly for the module, annotated with the `(source, entry_name)` of each kernel
+    /// if given.
+    fn write_assembly(&self, machine: &TargetMachine, output: &OutputType, annotation: Option<&[(&str, &str)]>) -> () {
+        let file_type = LLVMCodeGenFileType::LLVMAssemblyFile;
+        let name: &str = match *output {
+            OutputType::Stdout  => "Stdout",
+            OutputType::File(f) => f,
+        };
+        let result: bool = match (*output, annotation) {
+            (OutputType::Stdout, None)      => machine.emit_to_stdout(self, file_type),
+            (OutputType::File(f), None)     => machine.emit_to_file(self, f, file_type),
+            (_, Some(kernels))              => match machine.emit_to_buffer(self, file_type) {
+                Some(bytes) => {
+                    let asm = String::from_utf8_lossy(&bytes);
+                    let asm = Self::annotate_assembly(&asm, kernels, machine.get_comment_prefix());
+                    match *output {
+                        OutputType::Stdout  => {
+                            print!("{}", asm);
+                            true
+                        },
+                        OutputType::File(f) => Self::write_string_to_file(f, &asm),
+                    }
+                },
+                None        => false,
+            },
+        };
+        if !result {
+            eprintln!("Failed to write assembly file '{}'", name);
             exit(ExitCode::WriteError);
-        } else if result_llc.stdout.is_some() {
-            println!("{}", result_llc.stdout.unwrap());
         }
     }
 
-    fn executable_file_from_object(&self, f_bin: &str, f_obj: &str) -> () {
+    /// Emits the object file with the target machine used for optimization (i.e., with the same
+    /// relocation model and optimization level).
+    fn write_object_file(&self, machine: &TargetMachine, f_obj: &str) -> () {
+        if !machine.emit_to_file(self, f_obj, LLVMCodeGenFileType::LLVMObjectFile) {
+            eprintln!("Failed to write object file '{}'", f_obj);
+            exit(ExitCode::WriteError);
+        }
+    }
+
+    /// Emits the object file to memory (e.g., to be written to an archive with `--archive`).
+    pub fn emit_object(&self, machine: &TargetMachine) -> Vec<u8> {
+        match machine.emit_to_buffer(self, LLVMCodeGenFileType::LLVMObjectFile) {
+            Some(bytes) => bytes,
+            None        => {
+                eprintln!("Failed to emit object file for module '{}'", self.name);
+                exit(ExitCode::WriteError);
+            },
+        }
+    }
+
+    /// Links the object file `f_obj` (and the objects pushed to the bundle) with clang (for `triple`
+    /// if it is not empty) into the executable `f_bin`, or into a shared library if `shared` is set.
+    /// The `link_args` (e.g., `-l<lib>` and `-L<dir>`) are passed to clang after the objects.
+    fn link_file_from_object(
+        &self,
+        f_bin: &str,
+        f_obj: &str,
+        triple: &str,
+        shared: bool,
+        link_args: &[String],
+    ) -> () {
+        let target_arg = format!("--target={}", triple);
         let mut args: Vec<&str> = vec!["-o", f_bin, f_obj];
+        if !triple.is_empty() {
+            args.push(target_arg.as_str());
+        }
+        if shared {
+            args.push("-shared");
+        }
         for object in self.objects.iter() {
             args.push(object.as_str());
         }
+        for arg in link_args.iter() {
+            args.push(arg.as_str());
+        }
         let result_clang = Command::run("clang", &args);
         if !result_clang.success {
-            eprintln!("Failed to write executable file '{}' from object file '{}'", f_bin, f_obj);
+            let kind = if shared { "shared library" } else { "executable" };
+            eprintln!("Failed to write {} file '{}' from object file '{}'", kind, f_bin, f_obj);
             exit(ExitCode::WriteError);
         } else if result_clang.stdout.is_some() {
             println!("{}", result_clang.stdout.unwrap());
@@ -385,12 +652,23 @@ impl <'a> Drop for ModuleBundle<'a> {
         self.scope.clear();
         unsafe {
             LLVMDisposeBuilder(self.builder);
-            LLVMDisposeModule(self.module);
-            LLVMContextDispose(self.context);
+            if !self.module.is_null() {
+                LLVMDisposeModule(self.module);
+            }
         }
     }
 }
 
+/// The LLVM context of one or more bundles, disposed when the last bundle using it is dropped
+/// (i.e., after the modules and builders created in it).
+pub struct ContextOwner(LLVMContextRef);
+
+impl Drop for ContextOwner {
+    fn drop(&mut self) -> () {
+        unsafe { LLVMContextDispose(self.0) }
+    }
+}
+
 pub struct Scope {
     value_idx:  i32,
     vars:       HashMap<String, LLVMValueRef>,
@@ -4,42 +4,111 @@
 use std::fmt;
 
 pub struct RunOptions {
+    pub asm_source: bool,
+    pub ast_format: AstFormat,
     pub body_type: BodyType,
     pub codegen_type: CodeGenType,
+    pub debug_info: bool,
+    pub debug_passes: bool,
     pub drop_token: bool,
+    pub entry_name: String,
+    pub eval_mode: bool,
+    pub fmt_check: bool,
+    pub fmt_mode: bool,
+    pub header_file: String,
     pub host_arch: HostArch,
     pub host_os: HostOS,
+    pub input_format: InputFormat,
     pub ir_exit: bool,
     pub lex_exit: bool,
+    pub link_args: Vec<String>,
+    pub link_bitcode_files: Vec<String>,
+    pub link_objects: Vec<String>,
     pub no_target: bool,
+    pub no_unroll: bool,
+    pub no_vectorize: bool,
     pub opt_level: OptLevel,
     pub parse_exit: bool,
+    pub passes: String,
     pub print_ast: bool,
+    pub repl_mode: bool,
+    pub run_mode: bool,
+    pub rust_bindings_file: String,
     pub sem_exit: bool,
+    pub target_cpu: String,
+    pub target_features: String,
+    pub target_triple: String,
+    pub unicode_idents: bool,
     pub verbose: bool,
+    pub verify_each: bool,
 }
 
 impl RunOptions {
     pub fn new() -> Self {
         RunOptions{
+            asm_source: false,
+            ast_format: AstFormat::Unset,
             body_type: BodyType::Unset,
             codegen_type: CodeGenType::Unset,
+            debug_info: false,
+            debug_passes: false,
             drop_token: false,
+            entry_name: String::new(),
+            eval_mode: false,
+            fmt_check: false,
+            fmt_mode: false,
+            header_file: String::new(),
             host_arch: get_host_arch(),
             host_os: get_host_os(),
+            input_format: InputFormat::Source,
             ir_exit: false,
             lex_exit: false,
+            link_args: Vec::new(),
+            link_bitcode_files: Vec::new(),
+            link_objects: Vec::new(),
             no_target: false,
+            no_unroll: false,
+            no_vectorize: false,
             opt_level: OptLevel::O2,
             parse_exit: false,
+            passes: String::new(),
             print_ast: false,
+            repl_mode: false,
+            run_mode: false,
+            rust_bindings_file: String::new(),
             sem_exit: false,
+            target_cpu: String::new(),
+            target_features: String::new(),
+            target_triple: String::new(),
+            unicode_idents: false,
             verbose: false,
+            verify_each: false,
         }
     }
 
     pub fn early_exit(&self) -> bool {
-        self.ir_exit || self.lex_exit || self.parse_exit || self.sem_exit
+        self.no_codegen() || self.ir_exit || self.lex_exit || self.parse_exit || self.sem_exit
+    }
+
+    /// Returns the OS of the target triple (or of the host if no target triple was specified).
+    pub fn get_target_os(&self) -> HostOS {
+        let triple = self.target_triple.as_str();
+        if triple.is_empty() {
+            self.host_os
+        } else if triple.contains("linux") {
+            HostOS::Linux
+        } else if triple.contains("apple") || triple.contains("darwin") || triple.contains("macos") {
+            HostOS::MacOS
+        } else if triple.contains("windows") || triple.contains("win32") {
+            HostOS::Windows
+        } else {
+            HostOS::Unknown
+        }
+    }
+
+    /// Returns true if the selected mode only outputs text and never generates a module.
+    pub fn no_codegen(&self) -> bool {
+        self.ast_format != AstFormat::Unset || self.eval_mode || self.fmt_mode || self.repl_mode
     }
 }
 
@@ -47,19 +116,43 @@ impl fmt::Display for RunOptions {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let s_vec = vec![
             "RunOptions:".to_string(),
+            format!("asm_source: {}",   self.asm_source),
+            format!("ast_format: {}",   self.ast_format),
             format!("body_type: {}",    self.body_type),
             format!("codegen_type: {}", self.codegen_type),
+            format!("debug_info: {}",   self.debug_info),
+            format!("debug_passes: {}", self.debug_passes),
             format!("drop_token: {}",   self.drop_token),
+            format!("entry_name: {}",   self.entry_name),
+            format!("eval_mode: {}",    self.eval_mode),
+            format!("fmt_check: {}",    self.fmt_check),
+            format!("fmt_mode: {}",     self.fmt_mode),
+            format!("header_file: {}",  self.header_file),
             format!("host_arch: {}",    self.host_arch),
             format!("host_os: {}",      self.host_os),
+            format!("input_format: {}", self.input_format),
             format!("ir_exit: {}",      self.ir_exit),
             format!("lex_exit: {}",     self.lex_exit),
+            format!("link_args: [{}]", self.link_args.join(", ")),
+            format!("link_bitcode_files: [{}]", self.link_bitcode_files.join(", ")),
+            format!("link_objects: [{}]", self.link_objects.join(", ")),
             format!("no_target: {}",    self.no_target),
+            format!("no_unroll: {}",    self.no_unroll),
+            format!("no_vectorize: {}", self.no_vectorize),
             format!("opt_level: {}",    self.opt_level),
             format!("parse_exit: {}",   self.parse_exit),
+            format!("passes: {}",       self.passes),
             format!("print_ast: {}",    self.print_ast),
+            format!("repl_mode: {}",    self.repl_mode),
+            format!("run_mode: {}",     self.run_mode),
+            format!("rust_bindings_file: {}", self.rust_bindings_file),
             format!("sem_exit: {}",     self.sem_exit),
+            format!("target_cpu: {}",   self.target_cpu),
+            format!("target_features: {}", self.target_features),
+            format!("target_triple: {}", self.target_triple),
+            format!("unicode_idents: {}", self.unicode_idents),
             format!("verbose: {}",      self.verbose),
+            format!("verify_each: {}",  self.verify_each),
         ];
         write!(f, "{}", s_vec.join("\n    "))
     }
@@ -90,6 +183,24 @@ impl <'a> fmt::Display for OutputType<'a> {
     }
 }
 
+#[repr(u8)]
+#[derive(Clone,Copy,Default,PartialEq)]
+pub enum InputFormat {
+    #[default]
+    Source      = 0,
+    AstJson     = 1,
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            InputFormat::Source     => "InputFormat_Source",
+            InputFormat::AstJson    => "InputFormat_AstJson",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[repr(u8)]
 #[derive(Clone,Copy,Default)]
 pub enum OptLevel {
@@ -98,6 +209,8 @@ pub enum OptLevel {
     #[default]
     O2 = 2,     /// LLVM default opt level
     O3 = 3,
+    Os = 4,     // Optimize for size (O2 without optimizations that increase code size)
+    Oz = 5,     // Optimize aggressively for size
 }
 
 impl fmt::Display for OptLevel {
@@ -107,6 +220,30 @@ impl fmt::Display for OptLevel {
             OptLevel::O1 => "OptLevel_O1",
             OptLevel::O2 => "OptLevel_O2",
             OptLevel::O3 => "OptLevel_O3",
+            OptLevel::Os => "OptLevel_Os",
+            OptLevel::Oz => "OptLevel_Oz",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone,Copy,Default,PartialEq)]
+pub enum AstFormat {
+    #[default]
+    Unset       = 0,
+    Json        = 1,
+    Sexpr       = 2,
+    Dot         = 3,
+}
+
+impl fmt::Display for AstFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            AstFormat::Unset    => "AstFormat_Unset",
+            AstFormat::Json     => "AstFormat_Json",
+            AstFormat::Sexpr    => "AstFormat_Sexpr",
+            AstFormat::Dot      => "AstFormat_Dot",
         };
         write!(f, "{}", s)
     }
@@ -143,6 +280,9 @@ pub enum CodeGenType {
     Bitcode     = 2,
     Object      = 3,
     Executable  = 4,
+    Assembly    = 5,
+    SharedLib   = 6,
+    Archive     = 7,
 }
 
 impl fmt::Display for CodeGenType {
@@ -153,6 +293,9 @@ impl fmt::Display for CodeGenType {
             CodeGenType::Bitcode    => "CodeGen_Bitcode",
             CodeGenType::Object     => "CodeGen_Object",
             CodeGenType::Executable => "CodeGen_Executable",
+            CodeGenType::Assembly   => "CodeGen_Assembly",
+            CodeGenType::SharedLib  => "CodeGen_SharedLib",
+            CodeGenType::Archive    => "CodeGen_Archive",
         };
         write!(f, "{}", s)
     }
@@ -11,9 +11,10 @@ use crate::options;
 use ast::Ast;
 use ast::Expr;
 use ast::Operator;
-use exit_code::exit;
+use ast::Vars;
 use exit_code::ExitCode;
 use lex::token_kind_to_string;
+use lex::Span;
 use lex::Token;
 use lex::TokenKind;
 use options::RunOptions;
@@ -22,6 +23,7 @@ use options::RunOptions;
 pub struct ParserIter {
     token: Token,
     vars: Vec<String>,
+    var_spans: Vec<Span>,
     position: usize,
     end: usize,
 }
@@ -31,6 +33,7 @@ impl ParserIter {
         ParserIter{
             token: Default::default(),
             vars: Vec::new(),
+            var_spans: Vec::new(),
             position: 0,
             end,
         }
@@ -43,25 +46,22 @@ impl ParserIter {
 
 pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
+    source: &'a str,
     options: &'a RunOptions,
 }
 
 impl <'a> Parser<'a> {
-    pub fn new(tokens: &'a Vec<Token>, options: &'a RunOptions) -> Self {
-        if tokens.is_empty() {
-            eprintln!("Found empty program while parsing");
-            exit(ExitCode::ParserError);
-        }
-        Parser{tokens, options}
+    pub fn new(tokens: &'a Vec<Token>, source: &'a str, options: &'a RunOptions) -> Self {
+        Parser{tokens, source, options}
     }
 
     pub fn iter(&self) -> ParserIter {
         ParserIter::new(self.tokens.len())
     }
 
-    fn consume(&self, iter: &mut ParserIter, k: TokenKind, add_var: bool) -> bool {
-        let t: &Token = self.get_token(iter);
-        if t.is(k) {
+    fn consume(&self, iter: &mut ParserIter, k: TokenKind, add_var: bool) -> Result<bool, ExitCode> {
+        let t: &Token = self.get_token(iter)?;
+        Ok(if t.is(k) {
             if self.options.verbose {
                 eprintln!(
                     "Consumed expected token '{}' at position '{}'",
@@ -69,155 +69,275 @@ impl <'a> Parser<'a> {
                     iter.position
                 );
             }
-            iter.token = t.clone();
+            iter.token = *t;
             iter.position += 1;
             if add_var && t.is(TokenKind::Ident) {
-                iter.vars.push(t.text.clone()); 
+                iter.vars.push(t.text(self.source).to_string());
+                iter.var_spans.push(t.span);
             }
             true
         } else {
             false
-        }
+        })
     }
 
-    fn consume_one_of(&self, iter: &mut ParserIter, ks: &[TokenKind], add_var: bool) -> bool {
+    fn consume_one_of(&self, iter: &mut ParserIter, ks: &[TokenKind], add_var: bool) -> Result<bool, ExitCode> {
         for k in ks {
-            if self.consume(iter, *k, add_var) { return true }
+            if self.consume(iter, *k, add_var)? { return Ok(true) }
         }
-        false
+        Ok(false)
     }
     
-    fn expect(&self, iter: &mut ParserIter, k: TokenKind, add_var: bool) -> () {
-        if !self.consume(iter, k, add_var) {
+    fn expect(&self, iter: &mut ParserIter, k: TokenKind, add_var: bool) -> Result<(), ExitCode> {
+        if !self.consume(iter, k, add_var)? {
             eprintln!("Expected '{}' token at position {}", token_kind_to_string(k), iter.position);
-            exit(ExitCode::ParserError);
+            return Err(ExitCode::ParserError);
         }
+        Ok(())
     }
 
-    fn get_prev_token(&'a self, iter: &'a mut ParserIter) -> &Token {
+    fn get_prev_token(&'a self, iter: &'a mut ParserIter) -> &'a Token {
         &iter.token
     }
 
-    fn get_token(&self, iter: &mut ParserIter) -> &Token {
+    fn get_token(&self, iter: &mut ParserIter) -> Result<&Token, ExitCode> {
         if iter.has_next() {
-            self.tokens.get(iter.position).unwrap()
+            Ok(self.tokens.get(iter.position).unwrap())
         } else {
             eprintln!("Token out of bounds at {}", iter.position);
-            exit(ExitCode::ParserError);
+            Err(ExitCode::ParserError)
+        }
+    }
+
+    /// Returns the span from the start of the token at `start` to the end of the previous token.
+    fn get_span_from(&self, iter: &mut ParserIter, start: usize) -> Span {
+        Span::new(start, self.get_prev_token(iter).span.end)
+    }
+
+    /// Returns true if the next tokens start a `name` declaration.
+    /// `name` is only a keyword at the start of a program, so it can still be used as a var name.
+    fn is_name_decl(&self, iter: &mut ParserIter) -> Result<bool, ExitCode> {
+        let t: &Token = self.get_token(iter)?;
+        let t_next: Option<&Token> = self.tokens.get(iter.position + 1);
+        Ok(t.is(TokenKind::Ident) && t.text(self.source) == "name" && t_next.is_some_and(|t| t.is(TokenKind::Colon)))
+    }
+
+    /// Returns true if the next tokens start an `extern` declaration.
+    /// `extern` is only a keyword when followed by the name of the function and `ParenL`, so it can
+    /// still be used as a var name.
+    fn is_extern_decl(&self, iter: &mut ParserIter) -> Result<bool, ExitCode> {
+        let t: &Token = self.get_token(iter)?;
+        let t_name: Option<&Token> = self.tokens.get(iter.position + 1);
+        let t_paren: Option<&Token> = self.tokens.get(iter.position + 2);
+        Ok(t.is(TokenKind::Ident) && t.text(self.source) == "extern" &&
+            t_name.is_some_and(|t| t.is(TokenKind::Ident)) &&
+            t_paren.is_some_and(|t| t.is(TokenKind::ParenL)))
+    }
+
+    /// Parses an `extern` declaration and returns the name and parameters of the function, and
+    /// the start of the declaration.
+    fn parse_extern_decl(&self, iter: &mut ParserIter) -> Result<(String, Vars, usize), ExitCode> {
+        self.expect(iter, TokenKind::Ident, false)?;
+        let start = self.get_prev_token(iter).span.start;
+        self.expect(iter, TokenKind::Ident, false)?;
+        let name = self.get_prev_token(iter).text(self.source).to_string();
+        self.expect(iter, TokenKind::ParenL, false)?;
+        let mut params = Vars::new();
+        if self.consume(iter, TokenKind::Ident, false)? {
+            params.push(self.get_prev_token(iter).text(self.source).to_string());
+            while self.consume(iter, TokenKind::Comma, false)? {
+                self.expect(iter, TokenKind::Ident, false)?;
+                params.push(self.get_prev_token(iter).text(self.source).to_string());
+            }
         }
+        self.expect(iter, TokenKind::ParenR, false)?;
+        Ok((name, params, start))
     }
 
     #[allow(clippy::redundant_allocation)]
-    fn parse_calc(&self, iter: &mut ParserIter) -> Box<&mut dyn Ast> {
+    fn parse_calc(&self, iter: &mut ParserIter) -> Result<Box<&mut dyn Ast>, ExitCode> {
+        let mut name: Option<(String, usize)> = None;
+        if self.is_name_decl(iter)? {
+            self.expect(iter, TokenKind::Ident, false)?;
+            let start = self.get_prev_token(iter).span.start;
+            self.expect(iter, TokenKind::Colon, false)?;
+            self.expect(iter, TokenKind::Ident, false)?;
+            name = Some((self.get_prev_token(iter).text(self.source).to_string(), start));
+            self.expect(iter, TokenKind::Colon, false)?;
+        }
+        let mut externs: Vec<(String, Vars, usize)> = Vec::new();
+        while self.is_extern_decl(iter)? {
+            externs.push(self.parse_extern_decl(iter)?);
+        }
+        let mut expr: Box<Expr> = self.parse_decl(iter)?;
+        for (name, params, start) in externs.into_iter().rev() {
+            let span = self.get_span_from(iter, start);
+            expr = Box::new(Expr::new_externdecl(name, params, Box::leak(expr)).with_span(span));
+        }
+        if let Some((name, start)) = name {
+            let span = self.get_span_from(iter, start);
+            expr = Box::new(Expr::new_namedecl(name, Box::leak(expr)).with_span(span));
+        }
+        Ok(Box::new(Box::leak(expr) as &mut dyn Ast))
+    }
+
+    fn parse_decl(&self, iter: &mut ParserIter) -> Result<Box<Expr<'_>>, ExitCode> {
         let mut expr: Box<Expr>;
-        if self.consume(iter, TokenKind::With, false) {
-            self.expect(iter, TokenKind::Colon, false);
-            self.expect(iter, TokenKind::Ident, true);
-            while self.consume(iter, TokenKind::Comma, false) {
-                self.expect(iter, TokenKind::Ident, true);
+        if self.consume(iter, TokenKind::With, false)? {
+            let start = self.get_prev_token(iter).span.start;
+            self.expect(iter, TokenKind::Colon, false)?;
+            self.expect(iter, TokenKind::Ident, true)?;
+            while self.consume(iter, TokenKind::Comma, false)? {
+                self.expect(iter, TokenKind::Ident, true)?;
             }
-            self.expect(iter, TokenKind::Colon, false);
-            expr = self.parse_expr(iter);
-            expr = Box::new(Expr::new_withdecl(iter.vars.clone(), Box::leak(expr)));
+            self.expect(iter, TokenKind::Colon, false)?;
+            expr = self.parse_expr(iter)?;
+            let span = self.get_span_from(iter, start);
+            expr = Box::new(
+                Expr::new_withdecl(iter.vars.clone(), Box::leak(expr))
+                    .with_span(span)
+                    .with_decl_spans(iter.var_spans.clone())
+            );
         } else {
-            expr = self.parse_expr(iter);
+            expr = self.parse_expr(iter)?;
         }
-        Box::new(Box::leak(expr) as &mut dyn Ast)
+        Ok(expr)
     }
 
-    fn parse_expr(&self, iter: &mut ParserIter) -> Box<Expr> {
-        let mut e_left: Box<Expr> = self.parse_term(iter);
-        while self.consume_one_of(iter, &[TokenKind::Plus, TokenKind::Minus], false) {
+    fn parse_expr(&self, iter: &mut ParserIter) -> Result<Box<Expr<'_>>, ExitCode> {
+        let start = self.get_token(iter)?.span.start;
+        let mut e_left: Box<Expr> = self.parse_term(iter)?;
+        while self.consume_one_of(iter, &[TokenKind::Plus, TokenKind::Minus], false)? {
             let e_op: Operator = match self.get_prev_token(iter).kind {
                 TokenKind::Plus     => Operator::Add,
                 TokenKind::Minus    => Operator::Sub,
                 _                   => {
                     eprintln!("Unxpected token");
-                    exit(ExitCode::ParserError);
+                    return Err(ExitCode::ParserError);
                 }
             };
-            let e_right: Box<Expr> = self.parse_term(iter);
-            e_left = Box::new(Expr::new_binop(e_op, Box::leak(e_left), Box::leak(e_right)));
+            let e_right: Box<Expr> = self.parse_term(iter)?;
+            let span = self.get_span_from(iter, start);
+            e_left = Box::new(Expr::new_binop(e_op, Box::leak(e_left), Box::leak(e_right)).with_span(span));
         }
-        e_left
+        Ok(e_left)
     }
 
-    fn parse_term(&self, iter: &mut ParserIter) -> Box<Expr> {
-        let mut e_left: Box<Expr> = self.parse_factor(iter);
-        while self.consume_one_of(iter, &[TokenKind::Star, TokenKind::Slash], false) {
+    fn parse_term(&self, iter: &mut ParserIter) -> Result<Box<Expr<'_>>, ExitCode> {
+        let start = self.get_token(iter)?.span.start;
+        let mut e_left: Box<Expr> = self.parse_factor(iter)?;
+        while self.consume_one_of(iter, &[TokenKind::Star, TokenKind::Slash], false)? {
             let e_op: Operator = match self.get_prev_token(iter).kind {
                 TokenKind::Star     => Operator::Mul, 
                 TokenKind::Slash    => Operator::Div,
                 _                   => {
                     eprintln!("Unxpected token");
-                    exit(ExitCode::ParserError);
+                    return Err(ExitCode::ParserError);
                 }
             };
-            let e_right: Box<Expr> = self.parse_factor(iter);
-            e_left = Box::new(Expr::new_binop(e_op, Box::leak(e_left), Box::leak(e_right)));
+            let e_right: Box<Expr> = self.parse_factor(iter)?;
+            let span = self.get_span_from(iter, start);
+            e_left = Box::new(Expr::new_binop(e_op, Box::leak(e_left), Box::leak(e_right)).with_span(span));
         }
-        e_left
+        Ok(e_left)
     }
 
     fn is_hex_number(text: &str) -> bool {
         text.len() >= 2 && "0x" == &text[0..2]
     }
 
-    fn str_to_number(text: &String) -> i64 {
+    fn str_to_number(text: &str) -> Result<i64, ExitCode> {
         let (result, msg) = if Self::is_hex_number(text) {
             (i64::from_str_radix(&text[2..], 16), "Failed to convert hexadecimal string")
         } else {
-            (i64::from_str(text.as_str()), "Failed to convert decimal string")
+            (i64::from_str(text), "Failed to convert decimal string")
         };
         match result {
-            Ok(n)   => n,
+            Ok(n)   => Ok(n),
             Err(e)  => {
                 eprintln!("Number '{}' failed parse: {}\n{}", text, e, msg);
-                exit(ExitCode::ParserError);
+                Err(ExitCode::ParserError)
             },
         }
     }
 
-    fn parse_factor(&self, iter: &mut ParserIter) -> Box<Expr> {
-        if self.consume(iter, TokenKind::Minus, false) {
+    fn parse_factor(&self, iter: &mut ParserIter) -> Result<Box<Expr<'_>>, ExitCode> {
+        if self.consume(iter, TokenKind::Minus, false)? {
             // NOTE: Implement unary minus as for identifiers as BinaryOp(Sub,0,..) and numbers as -<num>
-            if self.consume(iter, TokenKind::Number, false) {
-                let text = format!("-{}", self.get_prev_token(iter).text.clone());
-                let n = Self::str_to_number(&text);
-                Box::new(Expr::new_number(n))
-            } else if self.consume(iter, TokenKind::Ident, false) {
-                let zero = Box::new(Expr::new_number(0));
-                let ident = Box::new(Expr::new_ident(self.get_prev_token(iter).text.clone()));
-                Box::new(Expr::new_binop(Operator::Sub, Box::leak(zero), Box::leak(ident)))
-            } else if self.consume(iter, TokenKind::ParenL, false) {
-                let zero = Box::new(Expr::new_number(0));
-                let expr = self.parse_expr(iter);
-                self.expect(iter, TokenKind::ParenR, false);
-                Box::new(Expr::new_binop(Operator::Sub, Box::leak(zero), Box::leak(expr)))
+            // The zero operand is synthesized and is given an empty span at the minus token.
+            let start = self.get_prev_token(iter).span.start;
+            let zero_span = Span::new(start, start);
+            if self.consume(iter, TokenKind::Number, false)? {
+                let text = format!("-{}", self.get_prev_token(iter).text(self.source));
+                let n = Self::str_to_number(&text)?;
+                let span = self.get_span_from(iter, start);
+                Ok(Box::new(Expr::new_number(n).with_span(span)))
+            } else if self.consume(iter, TokenKind::Ident, false)? {
+                let zero = Box::new(Expr::new_number(0).with_span(zero_span));
+                let ident = self.parse_ident(iter)?;
+                let span = self.get_span_from(iter, start);
+                Ok(Box::new(Expr::new_binop(Operator::Sub, Box::leak(zero), Box::leak(ident)).with_span(span)))
+            } else if self.consume(iter, TokenKind::ParenL, false)? {
+                let zero = Box::new(Expr::new_number(0).with_span(zero_span));
+                let expr = self.parse_expr(iter)?;
+                self.expect(iter, TokenKind::ParenR, false)?;
+                let span = self.get_span_from(iter, start);
+                Ok(Box::new(Expr::new_binop(Operator::Sub, Box::leak(zero), Box::leak(expr)).with_span(span)))
             } else {
                 eprintln!("Unexpected token after Token:Minus");
-                exit(ExitCode::ParserError);
+                Err(ExitCode::ParserError)
             }
-        } else if self.consume(iter, TokenKind::Number, false) {
-            let n = Self::str_to_number(&self.get_prev_token(iter).text.clone());
-            Box::new(Expr::new_number(n))
-        } else if self.consume(iter, TokenKind::Ident, false) {
-            Box::new(Expr::new_ident(self.get_prev_token(iter).text.clone()))
-        } else if self.consume(iter, TokenKind::ParenL, false) {
-            let expr = self.parse_expr(iter);
-            self.expect(iter, TokenKind::ParenR, false);
-            expr
+        } else if self.consume(iter, TokenKind::Number, false)? {
+            let t: Token = *self.get_prev_token(iter);
+            let n = Self::str_to_number(t.text(self.source))?;
+            Ok(Box::new(Expr::new_number(n).with_span(t.span)))
+        } else if self.consume(iter, TokenKind::Ident, false)? {
+            self.parse_ident(iter)
+        } else if self.consume(iter, TokenKind::ParenL, false)? {
+            let expr = self.parse_expr(iter)?;
+            self.expect(iter, TokenKind::ParenR, false)?;
+            Ok(expr)
         } else {
             eprintln!("Unexpected token");
-            exit(ExitCode::ParserError);
+            Err(ExitCode::ParserError)
         }
     }
 
+    /// Parses the identifier just consumed, or a call to the function it names if it is followed
+    /// by `ParenL`.
+    fn parse_ident(&self, iter: &mut ParserIter) -> Result<Box<Expr<'_>>, ExitCode> {
+        let t: Token = *self.get_prev_token(iter);
+        let name = t.text(self.source).to_string();
+        if !self.consume(iter, TokenKind::ParenL, false)? {
+            return Ok(Box::new(Expr::new_ident(name).with_span(t.span)));
+        }
+        let mut args: Vec<&Expr> = Vec::new();
+        if !self.consume(iter, TokenKind::ParenR, false)? {
+            args.push(Box::leak(self.parse_expr(iter)?));
+            while self.consume(iter, TokenKind::Comma, false)? {
+                args.push(Box::leak(self.parse_expr(iter)?));
+            }
+            self.expect(iter, TokenKind::ParenR, false)?;
+        }
+        let span = self.get_span_from(iter, t.span.start);
+        Ok(Box::new(Expr::new_call(name, args).with_span(span)))
+    }
+
+    /// Parses the tokens of `parser` into `ret`.
+    /// Returns an error after the first syntax error (the error has already been reported).
     #[allow(clippy::redundant_allocation)]
-    pub fn parse_input(ret: &mut Box<&'a mut dyn Ast>, parser: &'a mut Parser<'a>, options: &RunOptions) {
+    pub fn parse_input(
+        ret: &mut Box<&'a mut dyn Ast>,
+        parser: &'a mut Parser<'a>,
+        options: &RunOptions
+    ) -> Result<(), ExitCode> {
+        if parser.tokens.is_empty() {
+            eprintln!("Found empty program while parsing");
+            return Err(ExitCode::ParserError);
+        }
         let mut iter = parser.iter();
-        *ret = parser.parse_calc(&mut iter);
+        *ret = parser.parse_calc(&mut iter)?;
         if options.print_ast { eprintln!("AST: {}", ret.to_string()); }
-        if options.parse_exit { exit(ExitCode::Ok); }
+        Ok(())
     }
 }
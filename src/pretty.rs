@@ -0,0 +1,242 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Print the AST back into canonical calc source for `calcc fmt`.
+//! Spacing is normalized, parentheses are emitted only where operator precedence or associativity
+//! requires them, and the comments collected by the lexer are re-attached around the expression.
+//! Comments found inside the expression are moved to the lines preceding it.
+
+use crate::ast;
+use crate::lex;
+
+use ast::op_to_symbol;
+use ast::Ast;
+use ast::AstVisitor;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Factor;
+use ast::Operator;
+use ast::Vars;
+use lex::Span;
+use lex::Token;
+use lex::TokenKind;
+
+const PRECEDENCE_WITHDECL   : u8 = 0;
+const PRECEDENCE_ADD_SUB    : u8 = 1;
+const PRECEDENCE_MUL_DIV    : u8 = 2;
+const PRECEDENCE_FACTOR     : u8 = 3;
+
+pub struct PrettyPrinter<'a> {
+    buffer: String,
+    source: &'a str,
+}
+
+impl <'a> PrettyPrinter<'a> {
+    fn new(source: &'a str) -> Self {
+        PrettyPrinter{buffer: String::new(), source}
+    }
+
+    /// The parser represents `-x` and `-(e)` as a subtraction from a synthesized zero (empty span).
+    fn is_unary_minus(op: &Operator, e_left: &Expr) -> bool {
+        match (op, e_left.get_expr()) {
+            (Operator::Sub, ExprKind::Factor(Factor::Number(0))) => e_left.get_span().is_empty(),
+            _                                                     => false,
+        }
+    }
+
+    fn precedence(e: &Expr) -> u8 {
+        match e.get_expr() {
+            ExprKind::Undefined                 => PRECEDENCE_FACTOR,
+            ExprKind::Factor(_)                 => PRECEDENCE_FACTOR,
+            ExprKind::BinaryOp(op, e_left, _)   => {
+                if Self::is_unary_minus(op, e_left) {
+                    PRECEDENCE_FACTOR
+                } else {
+                    Self::precedence_op(op)
+                }
+            },
+            ExprKind::WithDecl(_, _)            => PRECEDENCE_WITHDECL,
+            ExprKind::NameDecl(_, _)            => PRECEDENCE_WITHDECL,
+            ExprKind::ExternDecl(_, _, _)       => PRECEDENCE_WITHDECL,
+            ExprKind::Call(_, _)                => PRECEDENCE_FACTOR,
+        }
+    }
+
+    fn precedence_op(op: &Operator) -> u8 {
+        match op {
+            Operator::Add | Operator::Sub   => PRECEDENCE_ADD_SUB,
+            Operator::Div | Operator::Mul   => PRECEDENCE_MUL_DIV,
+        }
+    }
+
+    fn print_operand(&mut self, e: &Expr, parens: bool) -> bool {
+        match e.get_expr() {
+            ExprKind::WithDecl(_, _)    => {
+                eprintln!("Unexpected nested WithDecl expression while formatting");
+                return false;
+            },
+            ExprKind::NameDecl(_, _)    => {
+                eprintln!("Unexpected nested NameDecl expression while formatting");
+                return false;
+            },
+            ExprKind::ExternDecl(_, _, _) => {
+                eprintln!("Unexpected nested ExternDecl expression while formatting");
+                return false;
+            },
+            _                           => (),
+        }
+        if parens {
+            self.buffer.push('(');
+            let result = self.visit(e);
+            self.buffer.push(')');
+            result
+        } else {
+            self.visit(e)
+        }
+    }
+
+    fn print_expr_undefined(&mut self) -> bool {
+        false
+    }
+
+    fn print_expr_factor(&mut self, f: &Factor, span: Span) -> bool {
+        match f {
+            Factor::Ident(name) => self.buffer += name,
+            Factor::Number(n)   => {
+                if span.is_empty() {
+                    self.buffer += format!("{}", n).as_str();
+                } else {
+                    // Keep the spelling of the literal (e.g., hexadecimal), dropping any space after a sign
+                    let text: String = self.source[span.start..span.end].split_whitespace().collect();
+                    self.buffer += text.as_str();
+                }
+            },
+        };
+        true
+    }
+
+    fn print_expr_binop(&mut self, op: &Operator, e_left: &Expr, e_right: &Expr) -> bool {
+        if Self::is_unary_minus(op, e_left) {
+            self.buffer.push('-');
+            let parens = !matches!(e_right.get_expr(), ExprKind::Factor(Factor::Ident(_)) | ExprKind::Call(_, _));
+            return self.print_operand(e_right, parens);
+        }
+        let precedence = Self::precedence_op(op);
+        if !self.print_operand(e_left, Self::precedence(e_left) < precedence) {
+            return false;
+        }
+        self.buffer += format!(" {} ", op_to_symbol(op)).as_str();
+        self.print_operand(e_right, Self::precedence(e_right) <= precedence)
+    }
+
+    fn print_expr_withdecl(&mut self, vars: &Vars, e: &Expr) -> bool {
+        self.buffer += format!("with: {}: ", vars.join(", ")).as_str();
+        self.print_operand(e, false)
+    }
+
+    fn print_expr_namedecl(&mut self, name: &str, e: &Expr) -> bool {
+        self.buffer += format!("name: {}: ", name).as_str();
+        self.print_decl_child(e)
+    }
+
+    fn print_expr_externdecl(&mut self, name: &str, params: &Vars, e: &Expr) -> bool {
+        self.buffer += format!("extern {}({}) ", name, params.join(", ")).as_str();
+        self.print_decl_child(e)
+    }
+
+    fn print_expr_call(&mut self, name: &str, args: &[&Expr]) -> bool {
+        self.buffer += format!("{}(", name).as_str();
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                self.buffer += ", ";
+            }
+            if !self.print_operand(arg, false) {
+                return false;
+            }
+        }
+        self.buffer.push(')');
+        true
+    }
+
+    /// Prints the expression following a `name` or `extern` declaration (i.e., the next
+    /// declaration of the program, if any).
+    fn print_decl_child(&mut self, e: &Expr) -> bool {
+        match e.get_expr() {
+            ExprKind::WithDecl(vars, e)             => self.print_expr_withdecl(vars, e),
+            ExprKind::ExternDecl(name, params, e)   => self.print_expr_externdecl(name, params, e),
+            _                                       => self.print_operand(e, false),
+        }
+    }
+
+    fn push_line(out: &mut String, prev_line: &mut Option<usize>, first: usize, last: usize, text: &str) {
+        if let Some(line) = *prev_line {
+            if first > line + 1 {
+                out.push('\n');
+            }
+        }
+        out.push_str(text);
+        out.push('\n');
+        *prev_line = Some(last);
+    }
+
+    /// Returns the canonical source text for the program parsed from `tokens`.
+    /// Runs of blank lines between comments and the expression are collapsed into a single line.
+    pub fn format(ast: &dyn Ast, tokens: &[Token], comments: &[Token], source: &str) -> Option<String> {
+        let mut printer = PrettyPrinter::new(source);
+        if !ast.accept(&mut printer) {
+            eprintln!("AST failed to format");
+            return None;
+        }
+        let code: Vec<&Token> = tokens.iter().filter(|t| !t.is(TokenKind::Eoi)).collect();
+        let (code_start, code_end) = match (code.first(), code.last()) {
+            (Some(first), Some(last))   => (first.span.start, last.span.end),
+            _                           => {
+                eprintln!("Expected tokens while formatting");
+                return None;
+            },
+        };
+        let line_of = |offset: usize| -> usize { source[..offset].matches('\n').count() };
+        let text_of = |t: &Token| -> String { t.text(source).trim_end().to_string() };
+        let code_start_line = line_of(code_start);
+        let code_end_line = line_of(code_end);
+
+        let mut out = String::new();
+        let mut prev_line: Option<usize> = None;
+        let mut code_line = printer.buffer;
+        for c in comments.iter().filter(|c| c.span.end <= code_start) {
+            let line = line_of(c.span.start);
+            Self::push_line(&mut out, &mut prev_line, line, line, &text_of(c));
+        }
+        for c in comments.iter().filter(|c| c.span.end > code_start && c.span.start < code_end) {
+            Self::push_line(&mut out, &mut prev_line, code_start_line, code_start_line, &text_of(c));
+        }
+        let mut trailing = comments.iter().filter(|c| c.span.start >= code_end).peekable();
+        if let Some(c) = trailing.next_if(|c| line_of(c.span.start) == code_end_line) {
+            code_line = format!("{} {}", code_line, text_of(c));
+        }
+        Self::push_line(&mut out, &mut prev_line, code_start_line, code_end_line, &code_line);
+        for c in trailing {
+            let line = line_of(c.span.start);
+            Self::push_line(&mut out, &mut prev_line, line, line, &text_of(c));
+        }
+        Some(out)
+    }
+}
+
+impl <'a> AstVisitor for PrettyPrinter<'a> {
+    fn visit(&mut self, ast: &dyn Ast) -> bool {
+        if ast.is_expr() {
+            let expr: &ExprKind = ast.get_expr();
+            return match expr {
+                ExprKind::Undefined                     => self.print_expr_undefined(),
+                ExprKind::Factor(f)                     => self.print_expr_factor(f, ast.get_span()),
+                ExprKind::BinaryOp(op, e_left, e_right) => self.print_expr_binop(op, e_left, e_right),
+                ExprKind::WithDecl(vars, e)             => self.print_expr_withdecl(vars, e),
+                ExprKind::NameDecl(name, e)             => self.print_expr_namedecl(name, e),
+                ExprKind::ExternDecl(name, params, e)   => self.print_expr_externdecl(name, params, e),
+                ExprKind::Call(name, args)              => self.print_expr_call(name, args),
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Evaluate calc expressions interactively for `calcc repl`.
+//! Each line of Stdin is either an expression (with or without a `with` declaration) or one of the
+//! meta-commands in `HELP`.
+//! Values bound with `:set` are kept across lines and passed as the arguments of each program:
+//! the parameters are the vars of the `with` declaration if there is one, and otherwise the bound
+//! vars used by the expression (in order of first use).
+//! Each line is compiled in-process and evaluated by the interpreter (or by the JIT if `--run` was
+//! passed to `repl`).
+//! The errors of each stage are reported as they are found, and an invalid line does not end the
+//! session.
+
+use std::collections::BTreeMap;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::BufRead;
+use std::io::IsTerminal;
+use std::io::Write;
+
+use crate::ast;
+use crate::eval;
+use crate::exit_code;
+use crate::fold;
+use crate::irgen;
+use crate::jit;
+use crate::lex;
+use crate::module;
+use crate::options;
+use crate::parse;
+use crate::sem;
+use crate::target;
+
+use ast::Ast;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Factor;
+use ast::Vars;
+use eval::Interpreter;
+use exit_code::exit;
+use exit_code::ExitCode;
+use fold::ConstFold;
+use irgen::IRGen;
+use jit::Jit;
+use lex::Lexer;
+use lex::Token;
+use module::ModuleBundle;
+use options::RunOptions;
+use parse::Parser;
+use sem::Semantics;
+use target::Passes;
+use target::PassBuilder;
+use target::Target;
+use target::TargetMachine;
+
+static PROMPT: &str = "calcc> ";
+static MODULE_NAME: &str = "calcc";
+static KERNEL_NAME: &str = "calcc_main";
+
+static HELP: [&str; 7] = [
+    ":ast <E>           Print the AST of expression E",
+    ":help              Print this list of commands",
+    ":ir <E>            Print the IR of expression E (pre-optimization)",
+    ":opt <E>           Print the IR of expression E (post-optimization)",
+    ":quit              Exit the REPL (same as the end of Stdin)",
+    ":set <V> = <E>     Evaluate expression E and bind the result to var V",
+    ":vars              Print the bound vars",
+];
+
+/// The output of compiling a program in the REPL.
+#[derive(Clone,Copy,PartialEq)]
+enum Action {
+    Ast,
+    Eval,
+    Ir,
+    Opt,
+}
+
+pub struct Repl<'a> {
+    bindings: BTreeMap<String, i64>,
+    options: &'a RunOptions,
+}
+
+impl <'a> Repl<'a> {
+    fn new(options: &'a RunOptions) -> Self {
+        Repl{bindings: BTreeMap::new(), options}
+    }
+
+    /// Collects the identifiers used in `expr` into `idents` in order of first use (without
+    /// duplicates).
+    fn collect_idents(expr: &ExprKind, idents: &mut Vars) -> () {
+        match expr {
+            ExprKind::Factor(Factor::Ident(name))   => if !idents.contains(name) {
+                idents.push(name.clone());
+            },
+            ExprKind::BinaryOp(_, e_left, e_right)  => {
+                Self::collect_idents(e_left.get_expr(), idents);
+                Self::collect_idents(e_right.get_expr(), idents);
+            },
+            ExprKind::Call(_, args)                 => for arg in args.iter() {
+                Self::collect_idents(arg.get_expr(), idents);
+            },
+            ExprKind::WithDecl(_, e)                |
+            ExprKind::NameDecl(_, e)                |
+            ExprKind::ExternDecl(_, _, e)           => Self::collect_idents(e.get_expr(), idents),
+            ExprKind::Factor(Factor::Number(_))     |
+            ExprKind::Undefined                     => (),
+        }
+    }
+
+    /// Returns the program for `line` and its parameters (i.e., `line` with a `with` declaration
+    /// of the identifiers it uses if it does not have one).
+    fn make_program(&self, line: &str) -> Option<(String, Vars)> {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut comments: Vec<Token> = Vec::new();
+        let mut expr_tmp: Expr = Default::default();
+        let mut ast: Box<&mut dyn Ast> = Box::new(&mut expr_tmp);
+        let mut lex = Lexer::new(line, self.options);
+        Lexer::lex_input(&mut tokens, &mut comments, &mut lex, self.options).ok()?;
+        let mut parser = Parser::new(&tokens, line, self.options);
+        Parser::parse_input(&mut ast, &mut parser, self.options).ok()?;
+        match ast.get_expr() {
+            ExprKind::NameDecl(_, _)        => {
+                eprintln!("Name declarations are not supported by the 'repl' subcommand");
+                None
+            },
+            ExprKind::ExternDecl(_, _, _)   => {
+                eprintln!("Extern declarations are not supported by the 'repl' subcommand");
+                None
+            },
+            ExprKind::WithDecl(vars, _)     => Some((line.to_string(), vars.clone())),
+            expr                            => {
+                let mut vars: Vars = Vars::new();
+                Self::collect_idents(expr, &mut vars);
+                if vars.is_empty() {
+                    Some((line.to_string(), vars))
+                } else {
+                    Some((format!("with: {}: {}", vars.join(", "), line), vars))
+                }
+            },
+        }
+    }
+
+    /// Returns the values bound to `vars`.
+    fn get_values(&self, vars: &Vars) -> Option<Vec<i64>> {
+        let mut values: Vec<i64> = Vec::new();
+        for var in vars.iter() {
+            match self.bindings.get(var) {
+                Some(n) => values.push(*n),
+                None    => {
+                    eprintln!("Found unbound var '{}' (bind it with ':set {} = <E>')", var, var);
+                    return None;
+                },
+            }
+        }
+        Some(values)
+    }
+
+    /// Compiles `line` for `action` and returns the value of the program for `Action::Eval`.
+    /// Returns None after any error (which has already been reported).
+    fn compile(&self, line: &str, action: Action) -> Option<i64> {
+        let options = self.options;
+        let (program, vars) = self.make_program(line)?;
+        let values: Vec<i64> = match action {
+            Action::Eval    => self.get_values(&vars)?,
+            _               => Vec::new(),
+        };
+
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut comments: Vec<Token> = Vec::new();
+        let mut expr_tmp: Expr = Default::default();
+        let mut ast: Box<&mut dyn Ast> = Box::new(&mut expr_tmp);
+        let mut lex = Lexer::new(&program, options);
+        Lexer::lex_input(&mut tokens, &mut comments, &mut lex, options).ok()?;
+        let mut parser = Parser::new(&tokens, &program, options);
+        Parser::parse_input(&mut ast, &mut parser, options).ok()?;
+        if action == Action::Ast {
+            println!("AST: {}", ast.to_string());
+            return None;
+        }
+        Semantics::check_all(*ast, options).ok()?;
+        *ast = ConstFold::fold_all(*ast, options).ok()?;
+        if action == Action::Eval && !options.run_mode {
+            return Interpreter::eval_result(*ast, &values, options);
+        }
+
+        let module_name = String::from(MODULE_NAME);
+        let mut bundle = ModuleBundle::new(&module_name, options.verbose);
+        if !IRGen::gen(*ast, &mut bundle, KERNEL_NAME, options.opt_level, None) {
+            return None;
+        }
+        if !bundle.verify_module() {
+            eprintln!("IRGen module failed to verify");
+            return None;
+        }
+        if action == Action::Ir {
+            print!("{}", bundle);
+            return None;
+        }
+
+        let mut target = Target::new(&options.target_triple);
+        if action == Action::Eval && !target.is_host() {
+            eprintln!("Option '--run' is only supported for the host target (found '{}')", target.get_string());
+            return None;
+        }
+        let mut machine = TargetMachine::new(
+            &mut target,
+            options.opt_level,
+            &options.target_cpu,
+            &options.target_features,
+            options.verbose
+        );
+        let mut pass_builder = PassBuilder::new(options);
+        if !pass_builder.run(&mut bundle, &mut machine, Passes::from_options(options), options.no_target) {
+            return None;
+        }
+        match action {
+            Action::Eval    => Jit::call(&mut bundle, &values, options),
+            _               => {
+                print!("{}", bundle);
+                None
+            },
+        }
+    }
+
+    /// Evaluates `expr` with the bound vars and returns its value.
+    fn eval(&self, expr: &str) -> Option<i64> {
+        self.compile(expr, Action::Eval)
+    }
+
+    /// Prints the AST or IR of `expr`.
+    fn print(&self, expr: &str, action: Action) -> () {
+        let _ = self.compile(expr, action);
+    }
+
+    fn set(&mut self, arg: &str) -> () {
+        let (var, expr) = match arg.split_once('=') {
+            Some((var, expr))   => (var.trim(), expr.trim()),
+            None                => {
+                eprintln!("Expected ':set <V> = <E>'");
+                return;
+            },
+        };
+        if !Lexer::is_ident_text(var, self.options) {
+            eprintln!("Invalid var name '{}'", var);
+            return;
+        }
+        if let Some(n) = self.eval(expr) {
+            println!("{} = {}", var, n);
+            self.bindings.insert(var.to_string(), n);
+        }
+    }
+
+    /// Runs a single line of input and returns false if the REPL should exit.
+    fn run_line(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            return true;
+        }
+        let command = match line.strip_prefix(':') {
+            Some(command)   => command,
+            None            => {
+                if let Some(n) = self.eval(line) {
+                    println!("{}", n);
+                }
+                return true;
+            },
+        };
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg))   => (name, arg.trim()),
+            None                => (command, ""),
+        };
+        if arg.is_empty() && ["ast", "ir", "opt", "set"].contains(&name) {
+            eprintln!("Expected argument after ':{}'", name);
+            return true;
+        }
+        match name {
+            "ast"   => self.print(arg, Action::Ast),
+            "help"  => eprintln!("{}", HELP.join("\n")),
+            "ir"    => self.print(arg, Action::Ir),
+            "opt"   => self.print(arg, Action::Opt),
+            "quit"  => return false,
+            "set"   => self.set(arg),
+            "vars"  => for (var, n) in self.bindings.iter() {
+                println!("{} = {}", var, n);
+            },
+            _       => eprintln!("Unrecognized command ':{}' (see ':help')", name),
+        };
+        true
+    }
+
+    pub fn run(options: &RunOptions) -> ! {
+        let mut repl = Repl::new(options);
+        let interactive = stdin().is_terminal();
+        let mut lines = stdin().lock().lines();
+        loop {
+            if interactive {
+                print!("{}", PROMPT);
+                let _ = stdout().flush();
+            }
+            let line: String = match lines.next() {
+                Some(Ok(line))  => line,
+                Some(Err(msg))  => {
+                    eprintln!("Failed to read input: {}", msg);
+                    exit(ExitCode::LexerError);
+                },
+                None            => break,
+            };
+            if !repl.run_line(&line) {
+                break;
+            }
+            let _ = stdout().flush();
+        }
+        exit(ExitCode::Ok);
+    }
+}
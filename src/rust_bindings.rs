@@ -0,0 +1,109 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Generate Rust bindings for the kernel (or the kernels of an archive) with `--emit-rust-bindings`.
+//! The bindings declare the kernel in an `unsafe extern "C"` block (as required by the 2024
+//! edition) and wrap it in a safe function of the same name, with the parameters of the `with`
+//! declaration.
+//! They are meant to be included (e.g., with `include!`) by a crate which links the object file of
+//! the kernel (e.g., from a `build.rs` running `calcc -k -c` or `calcc --archive`).
+
+extern crate llvm_sys as llvm;
+use llvm::prelude::LLVMTypeRef;
+
+use crate::ast;
+use crate::exit_code;
+use crate::header;
+use crate::module;
+
+use ast::Vars;
+use exit_code::exit;
+use exit_code::ExitCode;
+use header::HeaderGen;
+use module::FunctionSignature;
+use module::ModuleBundle;
+
+/// Keywords of Rust which are valid calc identifiers (and must be used as raw identifiers).
+static RUST_KEYWORDS: [&str; 53] = [
+    "_", "Self", "abstract", "as", "async", "await", "become", "box", "break", "const",
+    "continue", "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for",
+    "gen", "if", "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override",
+    "priv", "pub", "ref", "return", "self", "static", "struct", "super", "trait", "true", "try",
+    "type", "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Keywords of Rust which cannot be raw identifiers (and cannot be used as kernel or parameter
+/// names).
+pub static RUST_KEYWORDS_NOT_RAW: [&str; 5] = ["_", "Self", "crate", "self", "super"];
+
+pub struct RustBindingsGen {}
+
+impl RustBindingsGen {
+    /// Returns the text of the bindings for the kernels, where each kernel is given by its
+    /// signature and the names of its parameters, and `producer` identifies the compiler.
+    pub fn gen(kernels: &[(&FunctionSignature, &Vars)], producer: &str) -> String {
+        let mut lines: Vec<String> = vec![HeaderGen::get_producer_comment(kernels, producer)];
+        for (f_sig, vars) in kernels {
+            lines.push(String::new());
+            lines.append(&mut Self::get_kernel_bindings(f_sig, vars));
+        }
+        lines.join("\n") + "\n"
+    }
+
+    fn get_kernel_bindings(f_sig: &FunctionSignature, vars: &Vars) -> Vec<String> {
+        let name: &str = &f_sig.name;
+        let ident: String = Self::get_ident(name);
+        let ffi_name = format!("__calcc_{}", name);
+        let t_ret = Self::get_rust_type(f_sig.t_ret);
+        let names: Vec<String> = HeaderGen::get_param_names(vars, f_sig.params.len(), Self::get_param_name);
+        let params: Vec<(String, &str)> = names.into_iter().zip(f_sig.params.iter()).map(|(name, t)| {
+            (name, Self::get_rust_type(*t))
+        }).collect();
+        let param_decls: Vec<String> = params.iter().map(|(var, t)| format!("{}: {}", var, t)).collect();
+        let param_uses: Vec<String> = params.iter().map(|(var, _)| var.clone()).collect();
+        vec![
+            "unsafe extern \"C\" {".to_string(),
+            format!("    #[link_name = \"{}\"]", name),
+            format!("    fn {}({}) -> {};", ffi_name, param_decls.join(", "), t_ret),
+            "}".to_string(),
+            String::new(),
+            format!("/// Calls the calc kernel `{}`.", name),
+            "#[allow(non_snake_case)]".to_string(),
+            "#[inline]".to_string(),
+            format!("pub fn {}({}) -> {} {{", ident, param_decls.join(", "), t_ret),
+            format!("    unsafe {{ {}({}) }}", ffi_name, param_uses.join(", ")),
+            "}".to_string(),
+        ]
+    }
+
+    fn get_rust_type(t: LLVMTypeRef) -> &'static str {
+        match ModuleBundle::type_name_from(t).as_str() {
+            "i32"       => "i32",
+            "i64"       => "i64",
+            type_name   => {
+                eprintln!("Unsupported type '{}' for Rust bindings", type_name);
+                exit(ExitCode::WriteError);
+            },
+        }
+    }
+
+    /// Returns `name` as a raw identifier if it is a keyword (the kernel names which cannot be raw
+    /// identifiers are rejected by the semantics check).
+    fn get_ident(name: &str) -> String {
+        if RUST_KEYWORDS.contains(&name) {
+            format!("r#{}", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Returns the name of parameter `var` (as a raw identifier if it is a keyword), or `None` if
+    /// it is not ASCII or is a keyword which cannot be a raw identifier.
+    fn get_param_name(var: &str) -> Option<String> {
+        if !var.is_ascii() || RUST_KEYWORDS_NOT_RAW.contains(&var) {
+            None
+        } else {
+            Some(Self::get_ident(var))
+        }
+    }
+}
@@ -1,11 +1,14 @@
 // Copyright 2024, Giordano Salvador
 // SPDX-License-Identifier: BSD-3-Clause
 
+use std::collections::HashMap;
 use std::collections::HashSet;
 
 use crate::ast;
 use crate::exit_code;
+use crate::header;
 use crate::options;
+use crate::rust_bindings;
 
 use ast::Ast;
 use ast::AstVisitor;
@@ -14,9 +17,11 @@ use ast::ExprKind;
 use ast::Factor;
 use ast::Operator;
 use ast::Vars;
-use exit_code::exit;
 use exit_code::ExitCode;
+use header::C_KEYWORDS;
+use options::BodyType;
 use options::RunOptions;
+use rust_bindings::RUST_KEYWORDS_NOT_RAW;
 
 pub struct Scope {
     vars: HashSet<String>, 
@@ -48,13 +53,14 @@ impl Scope {
 }
 
 struct DeclCheck<'a> {
+    externs: HashMap<String, usize>,
     scope: Scope,
     options: &'a RunOptions,
 }
 
 impl <'a> DeclCheck<'a> {
     pub fn new(options: &'a RunOptions) -> Self {
-        DeclCheck{scope: Scope::new(), options}
+        DeclCheck{externs: HashMap::new(), scope: Scope::new(), options}
     }
 
     pub fn check_expr_undefined(&self) -> bool {
@@ -82,6 +88,39 @@ impl <'a> DeclCheck<'a> {
         }
         self.visit(e)
     }
+
+    pub fn check_expr_namedecl(&mut self, name: &str, e: &Expr) -> bool {
+        Semantics::check_entry_name(name, self.options) && self.visit(e)
+    }
+
+    pub fn check_expr_externdecl(&mut self, name: &str, params: &Vars, e: &Expr) -> bool {
+        if !Semantics::check_symbol_name(name, "Extern function") {
+            return false;
+        }
+        if self.externs.insert(name.to_string(), params.len()).is_some() {
+            eprintln!("Tried to declare extern function {} more than once", name);
+            return false;
+        }
+        if self.options.verbose {
+            eprintln!("Declared extern function '{}' with {} params", name, params.len());
+        }
+        self.visit(e)
+    }
+
+    pub fn check_expr_call(&mut self, name: &str, args: &[&Expr]) -> bool {
+        match self.externs.get(name) {
+            Some(n) if *n == args.len() => (),
+            Some(n)                     => {
+                eprintln!("Extern function '{}' expects {} args (found {})", name, n, args.len());
+                return false;
+            },
+            None                        => {
+                eprintln!("Found call to undeclared function '{}' (declare it with 'extern {}(...)')", name, name);
+                return false;
+            },
+        };
+        args.iter().all(|arg| self.visit(*arg))
+    }
 }
 
 impl <'a> AstVisitor for DeclCheck<'a> {
@@ -93,23 +132,96 @@ impl <'a> AstVisitor for DeclCheck<'a> {
                 ExprKind::Factor(f)                     => self.check_expr_factor(f),
                 ExprKind::BinaryOp(op, e_left, e_right) => self.check_expr_binop(op, e_left, e_right),
                 ExprKind::WithDecl(vars, e)             => self.check_expr_withdecl(vars, e),
+                ExprKind::NameDecl(name, e)             => self.check_expr_namedecl(name, e),
+                ExprKind::ExternDecl(name, params, e)   => self.check_expr_externdecl(name, params, e),
+                ExprKind::Call(name, args)              => self.check_expr_call(name, args),
             }
         }
         false
     }
 }
 
+/// Symbols declared by the generated main module, which cannot be used as the kernel name (or as
+/// the name of an extern function).
+static RESERVED_NAMES: [&str; 6] = ["atoll", "fprintf", "main", "printf", "stderr", "strcmp"];
+
+/// Names declared by the C main templates of `-C` (i.e., their macros and the locals of `main`)
+/// and by the headers they include (i.e., the functions, macros and types of `stdio.h`, `stdlib.h`
+/// and `string.h` in C11), which cannot be used as the kernel name with `-C`.
+static C_MAIN_NAMES: [&str; 139] = [
+    "BASE", "BUFSIZ", "EOF", "EXIT_FAILURE", "EXIT_SUCCESS", "FILE", "FILENAME_MAX", "FOPEN_MAX",
+    "L_tmpnam", "MB_CUR_MAX", "NULL", "NUM_ARGS", "RAND_MAX", "SEEK_CUR", "SEEK_END", "SEEK_SET",
+    "TMP_MAX", "USAGE", "_Exit", "_IOFBF", "_IOLBF", "_IONBF", "abort", "abs", "aligned_alloc",
+    "argc", "argv", "at_quick_exit", "atexit", "atof", "atoi", "atol", "atoll", "bsearch", "calloc",
+    "clearerr", "div", "div_t", "exit", "fclose", "feof", "ferror", "fflush", "fgetc", "fgetpos",
+    "fgets", "fopen", "fpos_t", "fprintf", "fputc", "fputs", "fread", "free", "freopen", "fscanf",
+    "fseek", "fsetpos", "ftell", "fwrite", "getc", "getchar", "getenv", "gets", "labs", "ldiv",
+    "ldiv_t", "llabs", "lldiv", "lldiv_t", "malloc", "mblen", "mbstowcs", "mbtowc", "memchr",
+    "memcmp", "memcpy", "memmove", "memset", "perror", "printf", "putc", "putchar", "puts", "qsort",
+    "quick_exit", "rand", "realloc", "remove", "rename", "result", "rewind", "scanf", "setbuf",
+    "setvbuf", "size_t", "snprintf", "sprintf", "srand", "sscanf", "stderr", "stdin", "stdout",
+    "strcat", "strchr", "strcmp", "strcoll", "strcpy", "strcspn", "strerror", "strlen", "strncat",
+    "strncmp", "strncpy", "strpbrk", "strrchr", "strspn", "strstr", "strtod", "strtof", "strtok",
+    "strtol", "strtold", "strtoll", "strtoul", "strtoull", "strxfrm", "system", "tmpfile", "tmpnam",
+    "ungetc", "vfprintf", "vfscanf", "vprintf", "vscanf", "vsnprintf", "vsprintf", "vsscanf",
+    "wcstombs", "wctomb",
+];
+
+/// Prefix of the symbols generated by the compiler (e.g., the JIT wrapper for `--run`).
+pub const RESERVED_PREFIX: &str = "__calcc_";
+
 pub struct Semantics {}
 
 impl Semantics {
-    pub fn check_all(ast: &dyn Ast, options: &RunOptions) -> bool {
+    /// Checks that `name` can be used as the symbol of the kernel (i.e., is a C identifier that
+    /// does not collide with the symbols of the main module, nor with the names of the code
+    /// generated for `options`: the C main of `-C`, the C header, and the Rust bindings).
+    pub fn check_entry_name(name: &str, options: &RunOptions) -> bool {
+        let is_c_main = options.body_type == BodyType::MainGenC;
+        if !Self::check_symbol_name(name, "Kernel") {
+            false
+        } else if is_c_main && C_MAIN_NAMES.contains(&name) {
+            eprintln!("Kernel name '{}' is reserved (it is declared by the C main or its headers)", name);
+            false
+        } else if (is_c_main || !options.header_file.is_empty()) && C_KEYWORDS.contains(&name) {
+            eprintln!("Kernel name '{}' is reserved (it is a keyword or type of the generated C or C++ code)", name);
+            false
+        } else if !options.rust_bindings_file.is_empty() && RUST_KEYWORDS_NOT_RAW.contains(&name) {
+            eprintln!("Kernel name '{}' is reserved (it is a keyword of Rust which cannot be a raw identifier)", name);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Checks that `name` can be used as the symbol of a function of kind `what` (e.g., 'Kernel').
+    fn check_symbol_name(name: &str, what: &str) -> bool {
+        let mut chars = name.chars();
+        let is_ident = match chars.next() {
+            Some(c) => (c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            None    => false,
+        };
+        if !is_ident {
+            eprintln!("Invalid {} name '{}' (expected an ASCII identifier)", what.to_lowercase(), name);
+            false
+        } else if RESERVED_NAMES.contains(&name) {
+            eprintln!("{} name '{}' is reserved", what, name);
+            false
+        } else if name.starts_with(RESERVED_PREFIX) {
+            eprintln!("{} name '{}' is reserved (the prefix '{}' is used by the compiler)", what, name, RESERVED_PREFIX);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Returns an error if the AST fails any semantics check (the error has already been reported).
+    pub fn check_all(ast: &dyn Ast, options: &RunOptions) -> Result<(), ExitCode> {
         let mut decl_check: DeclCheck = DeclCheck::new(options);
-        let decl_result: bool = ast.accept(&mut decl_check);
-        if !decl_result {
+        if !ast.accept(&mut decl_check) {
             eprintln!("AST failed DeclCheck semantics check");
-            exit(ExitCode::SemanticError);
+            return Err(ExitCode::SemanticError);
         }
-        if options.sem_exit { exit(ExitCode::Ok); }
-        decl_result
+        Ok(())
     }
 }
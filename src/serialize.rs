@@ -0,0 +1,343 @@
+// Copyright 2024, Giordano Salvador
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Serialize the AST for `--emit-ast` in one of the documented, versioned formats.
+//! See the 'AST serialization' section of the README for the schema of each format.
+//! Bump `AST_FORMAT_VERSION` whenever the schema changes in a way that breaks existing readers.
+//! The Graphviz DOT output is meant for rendering only and is not versioned.
+
+use crate::ast;
+use crate::lex;
+use crate::options;
+
+use ast::op_to_string;
+use ast::op_to_symbol;
+use ast::Ast;
+use ast::AstVisitor;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Factor;
+use ast::Operator;
+use ast::Vars;
+use lex::Span;
+use options::AstFormat;
+
+pub const AST_FORMAT_NAME: &str = "calcc-ast";
+pub const AST_FORMAT_VERSION: u32 = 3;
+
+pub struct AstSerializer {
+    buffer: String,
+    format: AstFormat,
+}
+
+impl AstSerializer {
+    fn new(format: AstFormat) -> Self {
+        AstSerializer{buffer: String::new(), format}
+    }
+
+    /// Quotes `s` as a JSON string literal (also used for strings in S-expressions).
+    pub fn quote(s: &str) -> String {
+        let mut quoted = String::with_capacity(s.len() + 2);
+        quoted.push('"');
+        for c in s.chars() {
+            match c {
+                '"'                 => quoted.push_str("\\\""),
+                '\\'                => quoted.push_str("\\\\"),
+                '\n'                => quoted.push_str("\\n"),
+                '\r'                => quoted.push_str("\\r"),
+                '\t'                => quoted.push_str("\\t"),
+                c if c.is_control() => quoted.push_str(format!("\\u{:04x}", c as u32).as_str()),
+                c                   => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    /// Writes the span of the current node and closes it.
+    fn close_node(&mut self, span: Span) -> () {
+        match self.format {
+            AstFormat::Json => self.buffer += format!(
+                ",\"span\":{{\"start\":{},\"end\":{}}}}}",
+                span.start,
+                span.end
+            ).as_str(),
+            _               => self.buffer += format!(" (span {} {}))", span.start, span.end).as_str(),
+        }
+    }
+
+    fn write_expr_undefined(&mut self) -> bool {
+        eprintln!("Unexpected undefined expression in AST");
+        false
+    }
+
+    fn write_expr_factor(&mut self, f: &Factor, span: Span) -> bool {
+        let value: String = match (f, self.format) {
+            (Factor::Ident(name), AstFormat::Json)  => format!("\"ident\":{}", Self::quote(name)),
+            (Factor::Number(n), AstFormat::Json)    => format!("\"number\":{}", n),
+            (Factor::Ident(name), _)                => format!("(ident {})", Self::quote(name)),
+            (Factor::Number(n), _)                  => format!("(number {})", n),
+        };
+        match self.format {
+            AstFormat::Json => self.buffer += format!("{{\"kind\":\"Factor\",{}", value).as_str(),
+            _               => self.buffer += format!("(Factor {}", value).as_str(),
+        };
+        self.close_node(span);
+        true
+    }
+
+    fn write_expr_binop(&mut self, op: &Operator, e_left: &Expr, e_right: &Expr, span: Span) -> bool {
+        let (prefix, sep): (String, &str) = match self.format {
+            AstFormat::Json => (format!("{{\"kind\":\"BinaryOp\",\"op\":\"{}\",\"lhs\":", op_to_string(op)), ",\"rhs\":"),
+            _               => (format!("(BinaryOp {} ", op_to_string(op)), " "),
+        };
+        self.buffer += prefix.as_str();
+        if !self.visit(e_left) {
+            return false;
+        }
+        self.buffer += sep;
+        if !self.visit(e_right) {
+            return false;
+        }
+        self.close_node(span);
+        true
+    }
+
+    fn write_expr_withdecl(&mut self, vars: &Vars, e: &Expr, span: Span) -> bool {
+        let vars_quoted: Vec<String> = vars.iter().map(|var| Self::quote(var)).collect();
+        match self.format {
+            AstFormat::Json => self.buffer += format!(
+                "{{\"kind\":\"WithDecl\",\"vars\":[{}],\"expr\":",
+                vars_quoted.join(",")
+            ).as_str(),
+            _               => self.buffer += format!("(WithDecl (vars {}) ", vars_quoted.join(" ")).as_str(),
+        };
+        if !self.visit(e) {
+            return false;
+        }
+        self.close_node(span);
+        true
+    }
+
+    fn write_expr_namedecl(&mut self, name: &str, e: &Expr, span: Span) -> bool {
+        match self.format {
+            AstFormat::Json => self.buffer += format!(
+                "{{\"kind\":\"NameDecl\",\"name\":{},\"expr\":",
+                Self::quote(name)
+            ).as_str(),
+            _               => self.buffer += format!("(NameDecl (name {}) ", Self::quote(name)).as_str(),
+        };
+        if !self.visit(e) {
+            return false;
+        }
+        self.close_node(span);
+        true
+    }
+
+    fn write_expr_externdecl(&mut self, name: &str, params: &Vars, e: &Expr, span: Span) -> bool {
+        let params_quoted: Vec<String> = params.iter().map(|param| Self::quote(param)).collect();
+        match self.format {
+            AstFormat::Json => self.buffer += format!(
+                "{{\"kind\":\"ExternDecl\",\"name\":{},\"params\":[{}],\"expr\":",
+                Self::quote(name),
+                params_quoted.join(",")
+            ).as_str(),
+            _               => self.buffer += format!(
+                "(ExternDecl (name {}) (params{}) ",
+                Self::quote(name),
+                params_quoted.iter().map(|param| format!(" {}", param)).collect::<String>()
+            ).as_str(),
+        };
+        if !self.visit(e) {
+            return false;
+        }
+        self.close_node(span);
+        true
+    }
+
+    fn write_expr_call(&mut self, name: &str, args: &[&Expr], span: Span) -> bool {
+        match self.format {
+            AstFormat::Json => self.buffer += format!(
+                "{{\"kind\":\"Call\",\"name\":{},\"args\":[",
+                Self::quote(name)
+            ).as_str(),
+            _               => self.buffer += format!("(Call (name {}) (args", Self::quote(name)).as_str(),
+        };
+        for (i, arg) in args.iter().enumerate() {
+            match self.format {
+                AstFormat::Json => if i > 0 { self.buffer.push(',') },
+                _               => self.buffer.push(' '),
+            };
+            if !self.visit(*arg) {
+                return false;
+            }
+        }
+        match self.format {
+            AstFormat::Json => self.buffer.push(']'),
+            _               => self.buffer.push(')'),
+        };
+        self.close_node(span);
+        true
+    }
+
+    /// Returns the serialization of `ast` as a single line of text (including the trailing newline).
+    pub fn serialize(ast: &dyn Ast, format: AstFormat) -> Option<String> {
+        let mut serializer = AstSerializer::new(format);
+        let (header, footer): (String, &str) = match format {
+            AstFormat::Json     => (format!(
+                "{{\"format\":\"{}\",\"version\":{},\"root\":",
+                AST_FORMAT_NAME,
+                AST_FORMAT_VERSION
+            ), "}\n"),
+            AstFormat::Sexpr    => (format!("({} (version {}) ", AST_FORMAT_NAME, AST_FORMAT_VERSION), ")\n"),
+            AstFormat::Dot      => return DotWriter::write(ast),
+            AstFormat::Unset    => {
+                eprintln!("No AST serialization format specified");
+                return None;
+            },
+        };
+        serializer.buffer += header.as_str();
+        if !ast.accept(&mut serializer) {
+            eprintln!("AST failed to serialize");
+            return None;
+        }
+        serializer.buffer += footer;
+        Some(serializer.buffer)
+    }
+}
+
+impl AstVisitor for AstSerializer {
+    fn visit(&mut self, ast: &dyn Ast) -> bool {
+        if ast.is_expr() {
+            let expr: &ExprKind = ast.get_expr();
+            let span: Span = ast.get_span();
+            return match expr {
+                ExprKind::Undefined                     => self.write_expr_undefined(),
+                ExprKind::Factor(f)                     => self.write_expr_factor(f, span),
+                ExprKind::BinaryOp(op, e_left, e_right) => self.write_expr_binop(op, e_left, e_right, span),
+                ExprKind::WithDecl(vars, e)             => self.write_expr_withdecl(vars, e, span),
+                ExprKind::NameDecl(name, e)             => self.write_expr_namedecl(name, e, span),
+                ExprKind::ExternDecl(name, params, e)   => self.write_expr_externdecl(name, params, e, span),
+                ExprKind::Call(name, args)              => self.write_expr_call(name, args, span),
+            }
+        }
+        false
+    }
+}
+
+/// Writes the AST as a Graphviz DOT graph (render with e.g. `dot -Tsvg`).
+/// Operators are drawn as circles, identifiers as ellipses, literals as boxes, and the parameters
+/// declared by `with` (or `extern`) as dashed ellipses attached to the declaration node.
+/// Calls are drawn as diamonds with an edge to each argument.
+pub struct DotWriter {
+    buffer: String,
+    count: usize,
+}
+
+impl DotWriter {
+    fn new() -> Self {
+        DotWriter{buffer: String::new(), count: 0}
+    }
+
+    /// Adds a node to the graph and returns its id.
+    fn add_node(&mut self, label: &str, attrs: &str) -> usize {
+        let id = self.count;
+        self.buffer += format!("    n{} [label={}, {}];\n", id, AstSerializer::quote(label), attrs).as_str();
+        self.count += 1;
+        id
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, label: &str) -> () {
+        self.buffer += format!("    n{} -> n{} [label={}];\n", from, to, AstSerializer::quote(label)).as_str();
+    }
+
+    /// Visits `e` and connects the root node of its subgraph to `parent`.
+    fn add_child(&mut self, parent: usize, e: &Expr, label: &str) -> bool {
+        let id = self.count;
+        if !self.visit(e) {
+            return false;
+        }
+        self.add_edge(parent, id, label);
+        true
+    }
+
+    fn write_expr_undefined(&mut self) -> bool {
+        eprintln!("Unexpected undefined expression in AST");
+        false
+    }
+
+    fn write_expr_factor(&mut self, f: &Factor) -> bool {
+        match f {
+            Factor::Ident(name) => self.add_node(name, "shape=ellipse"),
+            Factor::Number(n)   => self.add_node(&n.to_string(), "shape=box"),
+        };
+        true
+    }
+
+    fn write_expr_binop(&mut self, op: &Operator, e_left: &Expr, e_right: &Expr) -> bool {
+        let id = self.add_node(&op_to_symbol(op), "shape=circle");
+        self.add_child(id, e_left, "lhs") && self.add_child(id, e_right, "rhs")
+    }
+
+    fn write_expr_withdecl(&mut self, vars: &Vars, e: &Expr) -> bool {
+        let id = self.add_node("with", "shape=box, style=rounded");
+        for var in vars {
+            let id_var = self.add_node(var, "shape=ellipse, style=dashed");
+            self.add_edge(id, id_var, "param");
+        }
+        self.add_child(id, e, "expr")
+    }
+
+    fn write_expr_namedecl(&mut self, name: &str, e: &Expr) -> bool {
+        let id = self.add_node(&format!("name {}", name), "shape=box, style=rounded");
+        self.add_child(id, e, "expr")
+    }
+
+    fn write_expr_externdecl(&mut self, name: &str, params: &Vars, e: &Expr) -> bool {
+        let id = self.add_node(&format!("extern {}", name), "shape=box, style=rounded");
+        for param in params {
+            let id_param = self.add_node(param, "shape=ellipse, style=dashed");
+            self.add_edge(id, id_param, "param");
+        }
+        self.add_child(id, e, "expr")
+    }
+
+    fn write_expr_call(&mut self, name: &str, args: &[&Expr]) -> bool {
+        let id = self.add_node(name, "shape=diamond");
+        for (i, arg) in args.iter().enumerate() {
+            if !self.add_child(id, arg, &format!("arg{}", i)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn write(ast: &dyn Ast) -> Option<String> {
+        let mut writer = DotWriter::new();
+        writer.buffer += "digraph calcc_ast {\n    ordering=out;\n";
+        if !ast.accept(&mut writer) {
+            eprintln!("AST failed to serialize");
+            return None;
+        }
+        writer.buffer += "}\n";
+        Some(writer.buffer)
+    }
+}
+
+impl AstVisitor for DotWriter {
+    fn visit(&mut self, ast: &dyn Ast) -> bool {
+        if ast.is_expr() {
+            let expr: &ExprKind = ast.get_expr();
+            return match expr {
+                ExprKind::Undefined                     => self.write_expr_undefined(),
+                ExprKind::Factor(f)                     => self.write_expr_factor(f),
+                ExprKind::BinaryOp(op, e_left, e_right) => self.write_expr_binop(op, e_left, e_right),
+                ExprKind::WithDecl(vars, e)             => self.write_expr_withdecl(vars, e),
+                ExprKind::NameDecl(name, e)             => self.write_expr_namedecl(name, e),
+                ExprKind::ExternDecl(name, params, e)   => self.write_expr_externdecl(name, params, e),
+                ExprKind::Call(name, args)              => self.write_expr_call(name, args),
+            }
+        }
+        false
+    }
+}
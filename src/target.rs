@@ -6,8 +6,13 @@ extern crate llvm_sys as llvm;
 use llvm::error::LLVMErrorRef;
 use llvm::error::LLVMDisposeErrorMessage;
 use llvm::error::LLVMGetErrorMessage;
+use llvm::core::LLVMDisposeMemoryBuffer;
 use llvm::core::LLVMDisposeMessage;
+use llvm::core::LLVMGetBufferSize;
+use llvm::core::LLVMGetBufferStart;
 use llvm::core::LLVMSetTarget;
+use llvm::prelude::LLVMBool;
+use llvm::prelude::LLVMMemoryBufferRef;
 use llvm::target::LLVM_InitializeAllAsmParsers;
 use llvm::target::LLVM_InitializeAllAsmPrinters;
 use llvm::target::LLVM_InitializeAllTargetInfos;
@@ -19,17 +24,26 @@ use llvm::target::LLVMTargetDataRef;
 use llvm::target_machine::LLVMCreateTargetDataLayout;
 use llvm::target_machine::LLVMCreateTargetMachineOptions;
 use llvm::target_machine::LLVMCreateTargetMachineWithOptions;
+use llvm::target_machine::LLVMCodeGenFileType;
 use llvm::target_machine::LLVMCodeGenOptLevel;
 use llvm::target_machine::LLVMCodeModel;
 use llvm::target_machine::LLVMDisposeTargetMachine;
 use llvm::target_machine::LLVMDisposeTargetMachineOptions;
 use llvm::target_machine::LLVMGetDefaultTargetTriple;
 use llvm::target_machine::LLVMGetFirstTarget;
+use llvm::target_machine::LLVMGetHostCPUFeatures;
+use llvm::target_machine::LLVMGetHostCPUName;
 use llvm::target_machine::LLVMGetTargetFromTriple;
+use llvm::target_machine::LLVMNormalizeTargetTriple;
 use llvm::target_machine::LLVMRelocMode;
+use llvm::target_machine::LLVMTargetMachineEmitToFile;
+use llvm::target_machine::LLVMTargetMachineEmitToMemoryBuffer;
+use llvm::target_machine::LLVMTargetHasTargetMachine;
 use llvm::target_machine::LLVMTargetMachineOptionsRef;
+use llvm::target_machine::LLVMTargetMachineOptionsSetCPU;
 use llvm::target_machine::LLVMTargetMachineOptionsSetCodeGenOptLevel;
 use llvm::target_machine::LLVMTargetMachineOptionsSetCodeModel;
+use llvm::target_machine::LLVMTargetMachineOptionsSetFeatures;
 use llvm::target_machine::LLVMTargetMachineOptionsSetRelocMode;
 use llvm::target_machine::LLVMTargetMachineRef;
 use llvm::target_machine::LLVMTargetRef;
@@ -37,8 +51,13 @@ use llvm::target_machine::LLVMTargetRef;
 use llvm::transforms::pass_builder::*;
 
 use std::ffi::c_char;
+use std::ffi::c_int;
 use std::ffi::CStr;
+use std::io::stderr;
+use std::io::stdout;
+use std::io::Write;
 use std::ptr;
+use std::slice;
 
 use crate::exit_code;
 use crate::module;
@@ -48,6 +67,7 @@ use exit_code::exit;
 use exit_code::ExitCode;
 use module::ModuleBundle;
 use options::OptLevel;
+use options::RunOptions;
 
 static mut INITIALIZED: bool = false;
 
@@ -59,11 +79,18 @@ pub struct Target {
 }
 
 impl Target {
-    pub fn new() -> Self {
+    /// Looks up the registered target for `triple` (or for the default target triple of the host
+    /// if `triple` is empty).
+    pub fn new(triple: &str) -> Self {
         Self::init();
-        let string: TargetString = unsafe { LLVMGetDefaultTargetTriple() };
+        let string: TargetString = if triple.is_empty() {
+            unsafe { LLVMGetDefaultTargetTriple() }
+        } else {
+            let triple_c = format!("{}\0", triple);
+            unsafe { LLVMNormalizeTargetTriple(triple_c.as_ptr() as *const c_char) }
+        };
         if string.is_null() {
-            eprintln!("Failed to get default target string");
+            eprintln!("Failed to get target string");
             exit(ExitCode::TargetError);
         }
         let mut target: LLVMTargetRef = unsafe { LLVMGetFirstTarget() };
@@ -90,6 +117,10 @@ impl Target {
             eprintln!("Failed to lookup target for target string '{}'", Self::string_from(&string));
             exit(ExitCode::TargetError);
         }
+        if unsafe { LLVMTargetHasTargetMachine(target) } == false as LLVMBool {
+            eprintln!("Target for target string '{}' does not support code generation", Self::string_from(&string));
+            exit(ExitCode::TargetError);
+        }
         Target{string, target}
     }
 
@@ -97,6 +128,33 @@ impl Target {
         Self::string_from(&self.string)
     }
 
+    /// Returns true if the target has the architecture, OS, and environment of the host (e.g.,
+    /// 'x86_64-unknown-linux-gnu' on an 'x86_64-pc-linux-gnu' host).
+    pub fn is_host(&self) -> bool {
+        let host_string: TargetString = unsafe { LLVMGetDefaultTargetTriple() };
+        let host = Self::string_from(&host_string);
+        unsafe { LLVMDisposeMessage(host_string); }
+        Self::get_system(&host) == Self::get_system(&self.get_string())
+    }
+
+    /// Returns the architecture, OS, and environment of `triple` (i.e., without the vendor and the
+    /// version of the OS, which do not change the code that can run on the host).
+    fn get_system(triple: &str) -> (&str, &str, &str) {
+        let mut parts = triple.split('-');
+        let arch = match parts.next().unwrap_or_default() {
+            "arm64"     => "aarch64",
+            arch        => arch,
+        };
+        let _vendor = parts.next();
+        let os = parts.next().unwrap_or_default().trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+        let os = match os {
+            "macos" | "macosx"  => "darwin",
+            os                  => os,
+        };
+        let env = parts.next().unwrap_or_default();
+        (arch, os, env)
+    }
+
     pub fn string_from(string: &TargetString) -> String {
         let c_str = unsafe { CStr::from_ptr(*string) };
         let result = match c_str.to_str() {
@@ -137,9 +195,21 @@ pub struct TargetMachine<'a> {
 }
 
 impl <'a> TargetMachine<'a> {
-    pub fn new(target: &'a mut Target, opt_level: OptLevel) -> Self {
+    /// Creates a target machine for the given CPU and feature string (e.g., '+avx2,-sse4a').
+    /// An empty CPU or feature string selects the default for the target, and the CPU 'native'
+    /// selects the CPU and features of the host.
+    /// Exits if the CPU or a feature is not recognized for the target.
+    pub fn new(target: &'a mut Target, opt_level: OptLevel, cpu: &str, features: &str, verbose: bool) -> Self {
+        let (cpu, features) = Self::resolve_cpu(target, cpu, features);
+        if verbose && (!cpu.is_empty() || !features.is_empty()) {
+            eprintln!("Using CPU '{}' with features '{}'", cpu, features);
+        }
+        let cpu_c = format!("{}\0", cpu);
+        let features_c = format!("{}\0", features);
         let machine_options: LLVMTargetMachineOptionsRef = unsafe { LLVMCreateTargetMachineOptions() };
         unsafe {
+            LLVMTargetMachineOptionsSetCPU(machine_options, cpu_c.as_ptr() as *const c_char);
+            LLVMTargetMachineOptionsSetFeatures(machine_options, features_c.as_ptr() as *const c_char);
             LLVMTargetMachineOptionsSetCodeModel(machine_options, LLVMCodeModel::LLVMCodeModelDefault);
             LLVMTargetMachineOptionsSetRelocMode(machine_options, LLVMRelocMode::LLVMRelocPIC);
             LLVMTargetMachineOptionsSetCodeGenOptLevel(machine_options, match opt_level {
@@ -147,16 +217,125 @@ impl <'a> TargetMachine<'a> {
                 OptLevel::O1    => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
                 OptLevel::O2    => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
                 OptLevel::O3    => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+                OptLevel::Os    => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+                OptLevel::Oz    => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
             });
         };
-        let machine = unsafe { LLVMCreateTargetMachineWithOptions(
-            target.target, 
+        // LLVM only warns about (and then ignores) the CPU and features it does not recognize
+        let (machine, warnings) = capture_stderr(|| unsafe { LLVMCreateTargetMachineWithOptions(
+            target.target,
             target.string,
             machine_options,
-        )};
+        )});
+        if warnings.contains("is not a recognized") {
+            eprintln!("Unrecognized CPU or features for target '{}'", target.get_string());
+            exit(ExitCode::TargetError);
+        }
         let data_layout = unsafe { LLVMCreateTargetDataLayout(machine) };
         TargetMachine{data_layout, machine, machine_options, target}
     }
+
+    fn resolve_cpu(target: &Target, cpu: &str, features: &str) -> (String, String) {
+        if cpu != "native" {
+            return (cpu.to_string(), features.to_string());
+        }
+        if !target.is_host() {
+            eprintln!("CPU 'native' is only supported for the host target (found '{}')", target.get_string());
+            exit(ExitCode::TargetError);
+        }
+        let (host_cpu, host_features) = unsafe {
+            let cpu_ptr: TargetString = LLVMGetHostCPUName();
+            let features_ptr: TargetString = LLVMGetHostCPUFeatures();
+            let result = (Target::string_from(&cpu_ptr), Target::string_from(&features_ptr));
+            LLVMDisposeMessage(cpu_ptr);
+            LLVMDisposeMessage(features_ptr);
+            result
+        };
+        if features.is_empty() {
+            (host_cpu, host_features)
+        } else {
+            (host_cpu, format!("{},{}", host_features, features))
+        }
+    }
+
+    fn print_error(error_ptr: *mut c_char) -> () {
+        if !error_ptr.is_null() {
+            unsafe {
+                let c_string = CStr::from_ptr(error_ptr as *const c_char);
+                eprintln!("{}", c_string.to_str().expect("Unable to read target machine error string"));
+                LLVMDisposeMessage(error_ptr);
+            }
+        }
+    }
+
+    /// Emits the module in `bundle` as an object or assembly file to the file `f`.
+    pub fn emit_to_file(&self, bundle: &ModuleBundle, f: &str, file_type: LLVMCodeGenFileType) -> bool {
+        let name = format!("{}\0", f);
+        let mut error_ptr: *mut c_char = ptr::null_mut();
+        let result: LLVMBool = unsafe {
+            LLVMTargetMachineEmitToFile(
+                self.machine,
+                bundle.module,
+                name.as_ptr() as *const c_char,
+                file_type,
+                &mut error_ptr as *mut *mut c_char,
+            )
+        };
+        Self::print_error(error_ptr);
+        result == false as LLVMBool
+    }
+
+    /// Emits the module in `bundle` as an object or assembly file to memory.
+    pub fn emit_to_buffer(&self, bundle: &ModuleBundle, file_type: LLVMCodeGenFileType) -> Option<Vec<u8>> {
+        let mut error_ptr: *mut c_char = ptr::null_mut();
+        let mut buffer: LLVMMemoryBufferRef = ptr::null_mut();
+        let result: LLVMBool = unsafe {
+            LLVMTargetMachineEmitToMemoryBuffer(
+                self.machine,
+                bundle.module,
+                file_type,
+                &mut error_ptr as *mut *mut c_char,
+                &mut buffer as *mut LLVMMemoryBufferRef,
+            )
+        };
+        Self::print_error(error_ptr);
+        if result != false as LLVMBool {
+            return None;
+        }
+        let bytes: Vec<u8> = unsafe {
+            slice::from_raw_parts(LLVMGetBufferStart(buffer) as *const u8, LLVMGetBufferSize(buffer)).to_vec()
+        };
+        unsafe { LLVMDisposeMemoryBuffer(buffer); }
+        Some(bytes)
+    }
+
+    /// Emits the module in `bundle` as an object or assembly file to Stdout.
+    pub fn emit_to_stdout(&self, bundle: &ModuleBundle, file_type: LLVMCodeGenFileType) -> bool {
+        let bytes: Vec<u8> = match self.emit_to_buffer(bundle, file_type) {
+            Some(bytes) => bytes,
+            None        => return false,
+        };
+        let mut out = stdout();
+        match out.write_all(&bytes).and_then(|_| out.flush()) {
+            Ok(())      => true,
+            Err(msg)    => {
+                eprintln!("Failed to write to Stdout: {}", msg);
+                false
+            },
+        }
+    }
+
+    /// Returns the line comment prefix of the assembly syntax for the target.
+    pub fn get_comment_prefix(&self) -> &'static str {
+        let triple = self.target.get_string();
+        if triple.starts_with("aarch64") || triple.starts_with("arm64") {
+            "//"
+        } else if triple.starts_with("arm") || triple.starts_with("thumb") {
+            "@"
+        } else {
+            "#"
+        }
+    }
 }
 
 impl <'a> Drop for TargetMachine<'a> {
@@ -169,20 +348,37 @@ impl <'a> Drop for TargetMachine<'a> {
     }
 }
 
-#[allow(dead_code)]
 #[derive(Clone)]
 pub enum Passes {
     Default(OptLevel),
     Pipeline(String),
 }
 
+impl Passes {
+    /// Returns the pipeline given by '--passes', or else the default pipeline for the optimization
+    /// level.
+    pub fn from_options(options: &RunOptions) -> Self {
+        if options.passes.is_empty() {
+            Passes::Default(options.opt_level)
+        } else {
+            Passes::Pipeline(options.passes.clone())
+        }
+    }
+}
+
 pub struct PassBuilder {
     builder: LLVMPassBuilderOptionsRef,
 }
 
 impl PassBuilder {
-    pub fn new() -> Self {
+    pub fn new(options: &RunOptions) -> Self {
         let builder = unsafe { LLVMCreatePassBuilderOptions() };
+        unsafe {
+            LLVMPassBuilderOptionsSetDebugLogging(builder, options.debug_passes as LLVMBool);
+            LLVMPassBuilderOptionsSetVerifyEach(builder, options.verify_each as LLVMBool);
+            LLVMPassBuilderOptionsSetLoopUnrolling(builder, !options.no_unroll as LLVMBool);
+            LLVMPassBuilderOptionsSetLoopVectorization(builder, !options.no_vectorize as LLVMBool);
+        }
         PassBuilder{builder}
     }
 
@@ -199,8 +395,10 @@ impl PassBuilder {
                 OptLevel::O1    => "O1",
                 OptLevel::O2    => "O2",
                 OptLevel::O3    => "O3",
+                OptLevel::Os    => "Os",
+                OptLevel::Oz    => "Oz",
             }),
-            Passes::Pipeline(string)    => format!("{}\0", string),
+            Passes::Pipeline(ref string) => format!("{}\0", string),
         };
         unsafe {
             if !no_target {
@@ -216,7 +414,11 @@ impl PassBuilder {
             if !error.is_null() {
                 let error_msg_ptr: *mut c_char = LLVMGetErrorMessage(error);
                 let c_string = CStr::from_ptr(error_msg_ptr);
-                eprintln!("{}", c_string.to_str().expect("Could not read pass builder error string"));
+                let msg = c_string.to_str().expect("Could not read pass builder error string");
+                match passes {
+                    Passes::Default(_)          => eprintln!("{}", msg),
+                    Passes::Pipeline(string)    => eprintln!("Failed to run pass pipeline '{}': {}", string, msg),
+                };
                 LLVMDisposeErrorMessage(error_msg_ptr);
                 false
             } else {
@@ -231,3 +433,48 @@ impl Drop for PassBuilder {
         unsafe { LLVMDisposePassBuilderOptions(self.builder); }
     }
 }
+
+/// Runs `f` and returns its result with the text it wrote to Stderr (which is also written to
+/// Stderr), e.g., to check the warnings which LLVM prints while creating a target machine.
+#[cfg(unix)]
+fn capture_stderr<T>(f: impl FnOnce() -> T) -> (T, String) {
+    use std::io::pipe;
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+
+    extern "C" {
+        fn close(fd: c_int) -> c_int;
+        fn dup(fd: c_int) -> c_int;
+        fn dup2(fd: c_int, fd_new: c_int) -> c_int;
+    }
+
+    let stderr_fd: c_int = stderr().as_raw_fd();
+    let (mut reader, writer) = match pipe() {
+        Ok(ends)    => ends,
+        Err(_)      => return (f(), String::new()),
+    };
+    let _ = stderr().flush();
+    let saved_fd: c_int = unsafe { dup(stderr_fd) };
+    if saved_fd < 0 || unsafe { dup2(writer.as_raw_fd(), stderr_fd) } < 0 {
+        if saved_fd >= 0 {
+            unsafe { close(saved_fd); }
+        }
+        return (f(), String::new());
+    }
+    let result = f();
+    unsafe {
+        dup2(saved_fd, stderr_fd);
+        close(saved_fd);
+    }
+    drop(writer);
+    let mut text = String::new();
+    let _ = reader.read_to_string(&mut text);
+    eprint!("{}", text);
+    (result, text)
+}
+
+/// Runs `f` without capturing Stderr (i.e., the warnings of LLVM are only printed).
+#[cfg(not(unix))]
+fn capture_stderr<T>(f: impl FnOnce() -> T) -> (T, String) {
+    (f(), String::new())
+}
@@ -12,7 +12,7 @@ mod tests{
         String::from(path.to_str().unwrap())
     }
 
-    fn pathbuf_to_string(path: &PathBuf) -> String {
+    fn pathbuf_to_string(path: &Path) -> String {
         path.to_str().unwrap().to_string()
     }
 
@@ -35,24 +35,20 @@ mod tests{
         }).unwrap()
     }
 
-    fn get_shell(os_name: &String) -> String {
-        String::from(match os_name.as_str() {
+    fn get_shell(os_name: &str) -> String {
+        String::from(match os_name {
             "linux"     => "bash",
             "macos"     => "bash",
             "windows"   => "cmd",
-            _           => {
-                eprintln!("Unexpected target_os");
-                assert!(false);
-                ""
-            },
+            _           => panic!("Unexpected target_os"),
         })
     }
 
-    fn get_lit(os_name: &String) -> String {
+    fn get_lit(os_name: &str) -> String {
         let append_lit: fn(&Path) -> String = |path| {
             String::from(path.join("bin").join("lit").to_str().unwrap())
         };
-        match os_name.as_str() {
+        match os_name {
             "linux"     =>
                 match env::var("PYTHON_VENV_PATH") {
                     Ok(path)    => append_lit(Path::new(&path)),
@@ -69,18 +65,10 @@ mod tests{
             "windows"   => {
                 match env::var("PYTHON_VENV_PATH") {
                     Ok(path)    => append_lit(Path::new(&path)),
-                    Err(_)      => {
-                        eprintln!("No supported location for 'lit' found");
-                        assert!(false);
-                        String::new()
-                    },
+                    Err(_)      => panic!("No supported location for 'lit' found"),
                 }
             },
-            _           => {
-                eprintln!("OS not supported");
-                assert!(false);
-                String::new()
-            },
+            _           => panic!("OS not supported"),
         }
     }
 
@@ -118,12 +106,10 @@ mod tests{
         let arch: String = get_arch();
 
         if os_name.is_empty() {
-            eprintln!("Target OS '{}' not yet supported.", os_name);
-            assert!(false);
+            panic!("Target OS '{}' not yet supported.", os_name);
         }
         if arch.is_empty() {
-            eprintln!("Target arch '{}' not yet supported.", arch);
-            assert!(false);
+            panic!("Target arch '{}' not yet supported.", arch);
         }
 
         let calcc_dir: PathBuf = get_bin_dir();
@@ -143,7 +129,7 @@ mod tests{
 
         let calcc_dir_str: String = pathbuf_to_string(&calcc_dir);
         let lit_dir_str: String = pathbuf_to_string(&lit_dir);
-        let lit_bin_str: String = path_to_string(&lit_bin);
+        let lit_bin_str: String = path_to_string(lit_bin);
 
         let env_path_str: String = [
             calcc_dir_str,